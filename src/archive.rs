@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::db;
+use crate::messages::MessageEvent;
+
+/// A change queued for the archive's writer thread
+enum ArchiveWrite {
+    Store(Vec<MessageEvent>),
+    Remove(String),
+}
+
+/// Local archive of every message the bot has seen, with a full-text index.
+///
+/// Server-side search can't look inside end-to-end encrypted rooms, so the
+/// decrypted messages are kept here and searched locally. Writes are queued
+/// and applied by a writer thread, so they never block the async runtime.
+#[derive(Clone)]
+pub struct MessageArchive {
+    conn: Arc<Mutex<Connection>>,
+    writes: Sender<ArchiveWrite>,
+}
+
+impl MessageArchive {
+    /// Open the archive and start its writer thread
+    pub fn open(db_path: &str) -> Result<Self> {
+        // Ensure parent directory exists
+        if let Some(parent) = Path::new(db_path).parent() {
+            if !parent.exists() {
+                if let Err(e) = std::fs::create_dir_all(parent) {
+                    tracing::warn!("Failed to create database directory: {}", e);
+                }
+            }
+        }
+
+        let conn = Arc::new(Mutex::new(db::open(db_path)?));
+        let (writes, queue) = mpsc::channel();
+        let writer_conn = conn.clone();
+        std::thread::Builder::new()
+            .name("archive-writer".to_string())
+            .spawn(move || {
+                for write in queue {
+                    let mut conn = writer_conn.lock().unwrap_or_else(PoisonError::into_inner);
+                    let result = match write {
+                        ArchiveWrite::Store(messages) => store_messages(&mut conn, &messages),
+                        ArchiveWrite::Remove(event_id) => remove_message(&conn, &event_id),
+                    };
+                    if let Err(e) = result {
+                        tracing::warn!("Failed to update the message archive: {:#}", e);
+                    }
+                }
+            })
+            .context("Failed to start the archive writer")?;
+
+        Ok(Self { conn, writes })
+    }
+
+    /// Queue messages to be archived, replacing earlier versions of the same
+    /// events. Placeholders of undecryptable events are left out until decrypted.
+    pub fn store_messages(&self, messages: &[MessageEvent]) {
+        let messages: Vec<MessageEvent> = messages.iter().filter(|m| !m.is_undecryptable()).cloned().collect();
+        if !messages.is_empty() {
+            let _ = self.writes.send(ArchiveWrite::Store(messages));
+        }
+    }
+
+    /// Queue a redacted message to be removed from the archive
+    pub fn remove_message(&self, event_id: &str) {
+        let _ = self.writes.send(ArchiveWrite::Remove(event_id.to_string()));
+    }
+
+    /// Full-text search over message bodies in the given rooms, best matches first.
+    ///
+    /// Every word of `query` must appear in the message; FTS5 query syntax
+    /// is not interpreted.
+    /// Blocks on the database, so call it from a blocking task.
+    pub fn search(&self, query: &str, room_ids: &[String], limit: usize) -> Result<Vec<MessageEvent>> {
+        let Some(match_expr) = fts_match_expression(query) else {
+            return Ok(Vec::new());
+        };
+        if room_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Scope to the rooms in SQL, so the limit counts only their messages
+        let room_params = vec!["?"; room_ids.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT m.event_json
+             FROM archived_messages_fts
+             JOIN archived_messages m ON m.id = archived_messages_fts.rowid
+             WHERE archived_messages_fts MATCH ? AND m.room_id IN ({})
+             ORDER BY archived_messages_fts.rank
+             LIMIT ?",
+            room_params
+        ))?;
+        let params = std::iter::once(Value::from(match_expr))
+            .chain(room_ids.iter().map(|room_id| Value::from(room_id.clone())))
+            .chain(std::iter::once(Value::from(limit as i64)));
+        let rows = stmt.query_map(params_from_iter(params), |row| row.get::<_, String>(0))?;
+
+        let mut messages = Vec::new();
+        for event_json in rows {
+            messages.push(serde_json::from_str(&event_json?).context("Corrupt archived message")?);
+        }
+
+        Ok(messages)
+    }
+}
+
+fn store_messages(conn: &mut Connection, messages: &[MessageEvent]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO archived_messages (event_id, room_id, sender, timestamp, msgtype, body, event_json)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (event_id) DO UPDATE SET
+                msgtype = excluded.msgtype,
+                body = excluded.body,
+                event_json = excluded.event_json",
+        )?;
+        for message in messages {
+            let event_json = serde_json::to_string(message)?;
+            stmt.execute((
+                &message.event_id,
+                &message.room_id,
+                &message.sender,
+                message.timestamp as i64,
+                &message.msgtype,
+                &message.body,
+                event_json,
+            ))
+            .context("Failed to archive message")?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn remove_message(conn: &Connection, event_id: &str) -> Result<()> {
+    conn.execute("DELETE FROM archived_messages WHERE event_id = ?1", [event_id])
+        .context("Failed to remove archived message")?;
+
+    Ok(())
+}
+
+/// Quote each word of a user query as an FTS5 string so that operators and
+/// punctuation are matched literally
+fn fts_match_expression(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// An archive on its own database file, deleted when dropped
+    struct TempArchive {
+        archive: MessageArchive,
+        path: PathBuf,
+    }
+
+    impl TempArchive {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("matrix-web-archive-{}-{}.sqlite3", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self {
+                archive: MessageArchive::open(path.to_str().unwrap()).unwrap(),
+                path,
+            }
+        }
+
+        /// Archive messages right away instead of through the writer thread
+        fn store(&self, messages: &[MessageEvent]) {
+            let mut conn = self.archive.conn.lock().unwrap();
+            store_messages(&mut conn, messages).unwrap();
+        }
+    }
+
+    impl Drop for TempArchive {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn search_limit_applies_to_the_given_rooms_only() {
+        let temp = TempArchive::new("scope");
+        let other_rooms: Vec<_> = (0..5)
+            .map(|i| MessageEvent::text(&format!("$other{}", i), "!other:example.org", "@bob:example.org", "lunch plans"))
+            .collect();
+        temp.store(&other_rooms);
+        temp.store(&[MessageEvent::text("$mine", "!mine:example.org", "@alice:example.org", "lunch plans")]);
+
+        let results = temp.archive.search("lunch", &["!mine:example.org".to_string()], 3).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].event_id, "$mine");
+
+        assert!(temp.archive.search("lunch", &[], 3).unwrap().is_empty());
+        assert_eq!(temp.archive.search("lunch", &["!other:example.org".to_string()], 3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn query_syntax_is_matched_literally() {
+        assert_eq!(fts_match_expression("  "), None);
+        assert_eq!(fts_match_expression("a OR \"b"), Some("\"a\" \"OR\" \"\"\"b\"".to_string()));
+    }
+}
@@ -1,43 +1,99 @@
 use matrix_sdk::{
+    attachment::{
+        AttachmentConfig, AttachmentInfo, BaseAudioInfo, BaseFileInfo, BaseImageInfo,
+        BaseVideoInfo,
+    },
     config::SyncSettings,
+    deserialized_responses::TimelineEvent,
+    sync::SyncResponse,
     encryption::{
-        verification::{Verification},
-        EncryptionSettings,
+        verification::{
+            QrVerification, QrVerificationState, SasState, SasVerification, Verification,
+            VerificationRequest, VerificationRequestState,
+        },
+        BackupDownloadStrategy, EncryptionSettings,
     },
     matrix_auth::{MatrixSession, MatrixSessionTokens},
-    room::Room,
+    room::{MessagesOptions, Room},
     ruma::{
-        api::client::message::get_message_events,
-        events::room::message::{
-            MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+        api::client::{error::ErrorKind, session::get_login_types::v3::LoginType},
+        events::{
+            room::{
+                message::{
+                    AddMentions, ForwardThread, MessageType, OriginalRoomMessageEvent,
+                    OriginalSyncRoomMessageEvent, Relation, ReplacementMetadata, ReplyWithinThread,
+                    RoomMessageEvent, RoomMessageEventContent,
+                },
+                encrypted::{OriginalSyncRoomEncryptedEvent, RoomEncryptedEvent},
+                redaction::OriginalSyncRoomRedactionEvent,
+            },
+            reaction::{OriginalSyncReactionEvent, ReactionEvent, ReactionEventContent},
+            relation::Annotation,
+            key::verification::VerificationMethod,
+            AnyMessageLikeEvent, AnyTimelineEvent, AnyToDeviceEvent,
         },
-        UInt, UserId,
+        serde::Raw,
+        EventId, MilliSecondsSinceUnixEpoch, OwnedUserId, UInt, UserId,
     },
-    Client, LoopCtrl, SessionMeta,
+    reqwest, Client, LoopCtrl, SessionMeta,
     RoomMemberships,
 };
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock, Mutex};
+use tokio_stream::{Stream, StreamExt};
 use tracing::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use anyhow::Context;
+use crate::archive::MessageArchive;
+use crate::config::MessageHistoryConfig;
 use crate::credentials::CredentialStore;
+use crate::media::{self, AttachmentDimensions, MediaDownload, MediaError, MediaSources};
+use crate::recovery::{self, BackupStatus, SecretStorageStatus};
+use crate::passphrase::STORE_DATABASES;
+use crate::store_lock;
+use crate::messages::{
+    apply_edit, apply_reaction, remove_reaction, EncryptedSource, HistoryReload, MessageError,
+    MessageEvent, MessageOptions, ReactionUpdate, Redaction, RoomUpdate,
+};
+use crate::timeline::{cache_token_event, HistoryPage, RoomTimeline};
+use crate::verification::{self, VerificationError};
 
-// Constants for SAS verification retry logic
-const MAX_SAS_TRANSITION_ATTEMPTS: u32 = 150;
-const SAS_TRANSITION_RETRY_DELAY_MS: u64 = 2000;
-const MAX_VERIFICATION_DONE_RETRIES: usize = 10;
-const VERIFICATION_DONE_RETRY_DELAY_MS: u64 = 500;
+/// Upper bound for the `limit` of a history page
+const MAX_HISTORY_PAGE_SIZE: usize = 500;
 
-pub type MessageSender = broadcast::Sender<String>;
-pub type MessageReceiver = broadcast::Receiver<String>;
+/// How long room keys from the backup are collected before a room's history
+/// is reloaded, so a page of undecryptable messages causes one reload
+const ROOM_KEY_BATCH_DELAY_MS: u64 = 1000;
+
+pub type MessageSender = broadcast::Sender<RoomUpdate>;
+pub type MessageReceiver = broadcast::Receiver<RoomUpdate>;
+pub type VerificationReceiver = broadcast::Receiver<VerificationRequestInfo>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub room_id: String,
+    pub name: Option<String>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VerificationRequestInfo {
     pub request_id: String,
     pub other_user_id: String,
+    /// Empty for requests the bot sent to all devices of a user
     pub other_device_id: String,
     pub status: String,
+    /// Whether the bot sent the request, so the other side has to accept it
+    pub outgoing: bool,
+    /// The emoji or numbers to compare, once both sides exchanged keys
+    pub sas: Option<SasInfo>,
+    /// The other side can scan a QR code shown by the bot, once the request is ready
+    pub qr_show: bool,
+    /// The other side shows a QR code, which can be uploaded to the bot
+    pub qr_scan: bool,
+    /// Why the verification was cancelled
+    pub cancel_reason: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,17 +103,36 @@ pub struct SasInfo {
     pub decimals: Option<(u16, u16, u16)>,
 }
 
+/// How to obtain a Matrix session when connecting
+#[derive(Debug, Clone, Copy)]
+pub enum Login<'a> {
+    /// Restore the stored session, or log in with the stored password
+    Password(Option<&'a str>),
+    /// Complete an SSO login with the token the homeserver redirected back with
+    SsoToken(&'a str),
+    /// Take over an existing session of another client
+    AccessToken { access_token: &'a str, device_id: &'a str },
+}
+
 #[derive(Clone)]
 pub struct MatrixBot {
     homeserver: String,
     username: String,
-    room_id: String,
+    room_ids: Vec<String>,
     store_path: String,
     history_limit: usize,
+    history_cache_size: usize,
+    media_max_size: u64,
+    /// Shared by the SDK client and media downloads
+    http: reqwest::Client,
     client: Arc<Mutex<Option<Client>>>,
     message_tx: MessageSender,
-    message_history: Arc<RwLock<Vec<String>>>,
+    timelines: Arc<RwLock<HashMap<String, RoomTimeline>>>,
+    archive: Option<MessageArchive>,
     sync_handle: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    room_key_watchers: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+    verification_tx: broadcast::Sender<VerificationRequestInfo>,
+    verification_watchers: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
     verification_requests: Arc<RwLock<Vec<VerificationRequestInfo>>>,
     active_sas: Arc<RwLock<Option<SasInfo>>>,
 }
@@ -66,25 +141,35 @@ impl MatrixBot {
     pub fn new(
         homeserver: &str,
         username: &str,
-        room_id: &str,
-        history_limit: usize,
+        room_ids: Vec<String>,
+        history: &MessageHistoryConfig,
         store_path: &str,
+        media_max_size: u64,
+        archive: Option<MessageArchive>,
     ) -> (Self, MessageReceiver) {
         info!("Creating Matrix bot instance (not connected yet)");
         
         // Create broadcast channel for messages
         let (message_tx, message_rx) = broadcast::channel(100);
+        let (verification_tx, _) = broadcast::channel(16);
 
         let bot = MatrixBot {
             homeserver: homeserver.to_string(),
             username: username.to_string(),
-            room_id: room_id.to_string(),
+            room_ids,
             store_path: store_path.to_string(),
-            history_limit,
+            history_limit: history.limit,
+            history_cache_size: history.cache_size,
+            media_max_size,
+            http: media::http_client(),
             client: Arc::new(Mutex::new(None)),
             message_tx,
-            message_history: Arc::new(RwLock::new(Vec::with_capacity(history_limit))),
+            timelines: Arc::new(RwLock::new(HashMap::new())),
+            archive,
             sync_handle: Arc::new(Mutex::new(None)),
+            room_key_watchers: Arc::new(Mutex::new(Vec::new())),
+            verification_tx,
+            verification_watchers: Arc::new(Mutex::new(Vec::new())),
             verification_requests: Arc::new(RwLock::new(Vec::new())),
             active_sas: Arc::new(RwLock::new(None)),
         };
@@ -96,19 +181,150 @@ impl MatrixBot {
         self.client.lock().await.is_some()
     }
     
-    pub async fn connect(&self, matrix_password: &str, store_passphrase: &str, credentials_store: &CredentialStore) -> anyhow::Result<()> {
+    /// The first configured room, used by the legacy single-room endpoints
+    pub fn default_room_id(&self) -> &str {
+        &self.room_ids[0]
+    }
+    
+    pub fn has_room(&self, room_id: &str) -> bool {
+        self.room_ids.iter().any(|r| r == room_id)
+    }
+    
+    /// The configured rooms of this account
+    pub fn room_ids(&self) -> &[String] {
+        &self.room_ids
+    }
+    
+    /// Directory of the matrix-sdk SQLite store
+    pub fn store_path(&self) -> &str {
+        &self.store_path
+    }
+    
+    /// Largest attachment (in bytes) accepted for upload or download
+    pub fn media_max_size(&self) -> u64 {
+        self.media_max_size
+    }
+    
+    pub async fn connect(&self, login: Login<'_>, store_passphrase: &str, credentials_store: &CredentialStore) -> anyhow::Result<()> {
         // Check if already connected
         if self.is_connected().await {
             return Ok(());
         }
         
         info!("Connecting to Matrix with store passphrase...");
+        let client = self.build_client(store_passphrase).await?;
+
+        match login {
+            Login::Password(matrix_password) => {
+                self.restore_or_login(&client, matrix_password, store_passphrase, credentials_store).await?;
+            }
+            Login::SsoToken(token) => {
+                info!("Logging in with SSO login token");
+                client
+                    .matrix_auth()
+                    .login_token(token)
+                    .initial_device_display_name("Matrix Web Bot")
+                    .await?;
+                if let Some(user_id) = client.user_id().filter(|user_id| !self.is_account_user(user_id)) {
+                    let user_id = user_id.to_owned();
+                    if let Err(e) = client.matrix_auth().logout().await {
+                        warn!("Failed to log out the SSO session of {}: {}", user_id, e);
+                    }
+                    anyhow::bail!("Logged in as {} with SSO, not as the account's user {}", user_id, self.username);
+                }
+                Self::store_client_session(&client, store_passphrase, credentials_store).await;
+            }
+            Login::AccessToken { access_token, device_id } => {
+                let user_id = Self::access_token_owner(&self.homeserver, access_token, device_id).await?;
+                if !self.is_account_user(&user_id) {
+                    anyhow::bail!("The access token belongs to {}, not to the account's user {}", user_id, self.username);
+                }
+                info!("Taking over existing session of {} with device_id: {}", user_id, device_id);
+                client.matrix_auth().restore_session(MatrixSession {
+                    meta: SessionMeta {
+                        user_id,
+                        device_id: device_id.into(),
+                    },
+                    tokens: MatrixSessionTokens {
+                        access_token: access_token.to_string(),
+                        refresh_token: None,
+                    },
+                }).await?;
+                Self::store_client_session(&client, store_passphrase, credentials_store).await;
+            }
+        }
+
+        info!("Login successful");
+        
+        // The password is now known to be right, so credentials stored by
+        // earlier versions can be re-encrypted
+        if let Err(e) = credentials_store.migrate_legacy_secrets(store_passphrase).await {
+            warn!("Failed to re-encrypt stored credentials: {}", e);
+        }
+        
+        // Set up verification handlers
+        self.setup_verification_handlers(client.clone()).await;
+        
+        // Set up encryption and cross-signing
+        if let Err(e) = Self::setup_encryption(&client).await {
+            warn!("Failed to setup encryption: {}. You may need to verify this device via another session.", e);
+        }
+        
+        // Join rooms
+        for room_id in &self.room_ids {
+            let parsed_room_id = <&matrix_sdk::ruma::RoomId>::try_from(room_id.as_str())
+                .with_context(|| format!("Invalid room ID format: {}", room_id))?;
+            client.join_room_by_id(parsed_room_id).await?;
+            info!("Joined room: {}", room_id);
+        }
+        
+        // Perform initial sync to populate room state
+        info!("Performing initial sync to load room state");
+        client.sync_once(SyncSettings::default()).await?;
+        info!("Initial sync completed");
         
+        // Load message history for every room
+        for room_id in &self.room_ids {
+            let limit = self.history_limit.min(self.history_cache_size);
+            if let Err(e) = self.load_message_history_with_client(&client, room_id, limit).await {
+                // The cache keeps its gap, so the next history request retries
+                error!("Failed to load message history for {}: {:#}", room_id, e);
+            }
+        }
+        
+        // Reload history when keys arrive from the backup
+        self.watch_room_keys(&client).await;
+        
+        // Start sync in background
+        self.start_sync_with_client(client.clone()).await;
+        
+        // Store client
+        *self.client.lock().await = Some(client);
+        
+        info!("Bot connected and syncing");
+        Ok(())
+    }
+    
+    /// Restore the stored session without syncing, for subcommands that only
+    /// read history. Room state and keys are used as the last sync left them
+    /// in the store, and no handlers or watchers are started.
+    pub async fn open_session(&self, store_passphrase: &str, credentials_store: &CredentialStore) -> anyhow::Result<()> {
+        let client = self.build_client(store_passphrase).await?;
+        self.restore_or_login(&client, None, store_passphrase, credentials_store).await?;
+
+        *self.client.lock().await = Some(client);
+        Ok(())
+    }
+
+    /// Build a client on the account's Matrix store
+    async fn build_client(&self, store_passphrase: &str) -> anyhow::Result<Client> {
         // Configure encryption settings
         let encryption_settings = EncryptionSettings {
             auto_enable_cross_signing: true,
             auto_enable_backups: false,
-            ..Default::default()
+            // Once a key backup is enabled, keys of messages that can't be
+            // decrypted are downloaded from it
+            backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
         };
         
         // Use None for empty passphrase, Some for non-empty
@@ -118,13 +334,23 @@ impl MatrixBot {
             Some(store_passphrase)
         };
         
-        let client = Client::builder()
+        Ok(Client::builder()
             .homeserver_url(&self.homeserver)
             .sqlite_store(&self.store_path, store_passphrase_opt)
             .with_encryption_settings(encryption_settings)
+            .http_client(self.http.clone())
             .build()
-            .await?;
+            .await?)
+    }
 
+    /// Restore the stored session, or log in with the stored password
+    async fn restore_or_login(
+        &self,
+        client: &Client,
+        matrix_password: Option<&str>,
+        store_passphrase: &str,
+        credentials_store: &CredentialStore,
+    ) -> anyhow::Result<()> {
         // Check if we have an existing session to restore
         let session_exists = match credentials_store.session_exists() {
             Ok(exists) => exists,
@@ -136,12 +362,16 @@ impl MatrixBot {
         
         if session_exists {
             info!("Found existing session, attempting to restore...");
-            match credentials_store.get_session(store_passphrase) {
+            match credentials_store.get_session(store_passphrase).await {
                 Ok((device_id, access_token, user_id)) => {
                     info!("Restoring session with device_id: {}", device_id);
                     
-                    let user_id = user_id.as_str().try_into()
+                    let user_id: OwnedUserId = user_id.as_str().try_into()
                         .with_context(|| format!("Invalid user ID format: {}", user_id))?;
+                    // The first of several accounts takes over the single-account session
+                    if !self.is_account_user(&user_id) {
+                        anyhow::bail!("The stored session belongs to {}, not to the account's user {}", user_id, self.username);
+                    }
                     
                     let session = MatrixSession {
                         meta: SessionMeta {
@@ -157,77 +387,123 @@ impl MatrixBot {
                     client.matrix_auth().restore_session(session).await?;
                     info!("Session restored successfully");
                 }
+                Err(e) if matrix_password.is_none() => {
+                    return Err(e.context("Failed to restore session. Wrong SQLite password?"));
+                }
                 Err(e) => {
                     warn!("Failed to restore session: {}. Falling back to login.", e);
                     // Fall back to login if session restore fails
-                    self.login_and_store_session(&client, matrix_password, store_passphrase, credentials_store).await?;
+                    self.login_and_store_session(client, matrix_password, store_passphrase, credentials_store).await?;
                 }
             }
         } else {
             info!("No existing session found, logging in as {}", self.username);
-            self.login_and_store_session(&client, matrix_password, store_passphrase, credentials_store).await?;
-        }
-
-        info!("Login successful");
-        
-        // Set up verification handlers
-        self.setup_verification_handlers(client.clone()).await;
-        
-        // Set up encryption and cross-signing
-        if let Err(e) = Self::setup_encryption(&client).await {
-            warn!("Failed to setup encryption: {}. You may need to verify this device via another session.", e);
+            self.login_and_store_session(client, matrix_password, store_passphrase, credentials_store).await?;
         }
         
-        // Join room
-        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(self.room_id.as_str())?;
-        client.join_room_by_id(room_id).await?;
-        info!("Joined room: {}", self.room_id);
-        
-        // Perform initial sync to populate room state
-        info!("Performing initial sync to load room state");
-        client.sync_once(SyncSettings::default()).await?;
-        info!("Initial sync completed");
-        
-        // Load message history
-        self.load_message_history_with_client(&client, self.history_limit).await?;
-        
-        // Start sync in background
-        self.start_sync_with_client(client.clone()).await;
-        
-        // Store client
-        *self.client.lock().await = Some(client);
-        
-        info!("Bot connected and syncing");
         Ok(())
     }
-    
+
     /// Helper method to perform login and store session
     async fn login_and_store_session(
         &self,
         client: &Client,
-        matrix_password: &str,
+        matrix_password: Option<&str>,
         store_passphrase: &str,
         credentials_store: &CredentialStore,
     ) -> anyhow::Result<()> {
+        let Some(matrix_password) = matrix_password else {
+            anyhow::bail!("No stored session and no Matrix password to log in with; use SSO or an access token");
+        };
+
         client
             .matrix_auth()
             .login_username(&self.username, matrix_password)
             .initial_device_display_name("Matrix Web Bot")
             .await?;
         
-        // Save the session after successful login
+        Self::store_client_session(client, store_passphrase, credentials_store).await;
+        Ok(())
+    }
+
+    /// Save the session of a client that just logged in
+    async fn store_client_session(client: &Client, store_passphrase: &str, credentials_store: &CredentialStore) {
         if let Some(session) = client.session() {
             if let Err(e) = credentials_store.store_session(
                 session.meta().device_id.as_str(),
                 session.access_token(),
                 session.meta().user_id.as_str(),
                 store_passphrase,
-            ) {
+            ).await {
                 warn!("Failed to store session: {}", e);
             }
         }
-        
-        Ok(())
+    }
+
+    /// Whether `user_id` is the configured account user, whose username may
+    /// be a full user ID or just the localpart
+    fn is_account_user(&self, user_id: &UserId) -> bool {
+        if self.username.starts_with('@') {
+            user_id.as_str() == self.username
+        } else {
+            user_id.localpart() == self.username
+        }
+    }
+
+    /// Find the user an existing access token belongs to, checking that it
+    /// is the token of `device_id`
+    async fn access_token_owner(homeserver: &str, access_token: &str, device_id: &str) -> anyhow::Result<OwnedUserId> {
+        let client = Client::builder()
+            .homeserver_url(homeserver)
+            .build()
+            .await?;
+
+        // whoami only needs the token; the placeholder identity is replaced
+        // by what the server answers
+        client.matrix_auth().restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: OwnedUserId::try_from("@placeholder:localhost")?,
+                device_id: device_id.into(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: access_token.to_string(),
+                refresh_token: None,
+            },
+        }).await?;
+
+        let whoami = client.whoami().await.context("The homeserver rejected the access token")?;
+        if let Some(token_device) = whoami.device_id {
+            if token_device.as_str() != device_id {
+                anyhow::bail!(
+                    "The access token belongs to device {}, not {}",
+                    token_device,
+                    device_id
+                );
+            }
+        }
+
+        Ok(whoami.user_id)
+    }
+
+    /// Login flows the homeserver offers, with its SSO identity providers
+    pub async fn login_flows(&self) -> anyhow::Result<Vec<LoginType>> {
+        let client = Client::builder()
+            .homeserver_url(&self.homeserver)
+            .build()
+            .await?;
+
+        Ok(client.matrix_auth().get_login_types().await?.flows)
+    }
+
+    /// URL to send the browser to for SSO login; the homeserver redirects
+    /// back to `redirect_url` with a login token
+    pub async fn sso_login_url(&self, redirect_url: &str, idp_id: Option<&str>) -> anyhow::Result<String> {
+        let client = Client::builder()
+            .homeserver_url(&self.homeserver)
+            .build()
+            .await?;
+
+        Ok(client.matrix_auth().get_sso_login_url(redirect_url, idp_id).await?)
     }
     
     /// Disconnect from Matrix without clearing the session.
@@ -243,8 +519,7 @@ impl MatrixBot {
     /// 
     /// # Note
     /// This is appropriate for a "disconnect/reconnect" flow. For complete logout
-    /// with session cleanup, a separate method would need to be created that calls
-    /// logout() on the server and clears both the session and crypto store.
+    /// with session cleanup, use [`logout`](Self::logout).
     pub async fn disconnect(&self, _credentials_store: &CredentialStore) -> anyhow::Result<()> {
         info!("Disconnecting from Matrix...");
         
@@ -252,6 +527,14 @@ impl MatrixBot {
         if let Some(handle) = self.sync_handle.lock().await.take() {
             handle.abort();
         }
+        for watcher in self.room_key_watchers.lock().await.drain(..) {
+            watcher.abort();
+        }
+        for watcher in self.verification_watchers.lock().await.drain(..) {
+            watcher.abort();
+        }
+        self.verification_requests.write().await.clear();
+        *self.active_sas.write().await = None;
         
         // Note: We do NOT call logout() on the server or clear the stored session.
         // This allows the user to reconnect with the same device_id and session,
@@ -263,20 +546,80 @@ impl MatrixBot {
         // - On reconnect, the same session and device_id will be used
         // - The crypto store and session will remain synchronized
         // 
-        // This is the correct behavior for a "disconnect/reconnect" flow. If complete
-        // logout and session cleanup is needed in the future, a separate method should
-        // be created that calls logout() and clears both the session and crypto store.
+        // This is the correct behavior for a "disconnect/reconnect" flow. Complete
+        // logout and session cleanup is done by logout().
         
         // Remove the client reference but keep the session stored for reconnect
         *self.client.lock().await = None;
         
-        // Clear message history
-        self.message_history.write().await.clear();
+        // Clear the timeline cache
+        self.timelines.write().await.clear();
         
         info!("Bot disconnected (session preserved for reconnect)");
         Ok(())
     }
     
+    /// Log this device out of the homeserver and delete its local state.
+    ///
+    /// Unlike [`disconnect`](Self::disconnect), the access token is revoked on
+    /// the server, the stored session is cleared and the Matrix store
+    /// databases, holding the device's encryption keys, are deleted. With
+    /// `forget_credentials` the stored username and password are deleted too,
+    /// so the next login starts from scratch.
+    pub async fn logout(&self, credentials_store: &CredentialStore, forget_credentials: bool) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        
+        info!("Logging out this device...");
+        match client.matrix_auth().logout().await {
+            Ok(_) => info!("Device logged out on the homeserver"),
+            // The server already dropped the session, only the local cleanup is left
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. })) => {
+                warn!("Access token was already invalid, deleting local session");
+            }
+            Err(e) => return Err(e).context("Failed to log out on the homeserver"),
+        }
+        
+        self.disconnect(credentials_store).await?;
+        drop(client);
+        
+        credentials_store.clear_session()?;
+        if forget_credentials {
+            credentials_store.clear_credentials()?;
+        }
+        
+        self.delete_store()?;
+        
+        info!("Logged out, session and Matrix store deleted");
+        Ok(())
+    }
+    
+    /// Delete the matrix-sdk databases of the store directory, and the
+    /// directory once it is empty. Nothing else is touched, so a store path
+    /// pointing at a shared directory can't take other files with it.
+    fn delete_store(&self) -> anyhow::Result<()> {
+        let store_path = std::path::Path::new(&self.store_path);
+        
+        for database in STORE_DATABASES {
+            for suffix in ["", "-wal", "-shm"] {
+                let path = store_path.join(format!("{}{}", database, suffix));
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).with_context(|| format!("Failed to delete {}", path.display())),
+                }
+            }
+        }
+        
+        match std::fs::remove_dir(store_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            // The lock file stays while this process holds the store
+            Err(_) if store_lock::holds_only_lock(store_path) => {}
+            Err(_) => warn!("Kept {}: it holds files that aren't part of the Matrix store", self.store_path),
+        }
+        Ok(())
+    }
+    
     async fn setup_encryption(client: &Client) -> anyhow::Result<()> {
         let encryption = client.encryption();
         
@@ -293,12 +636,13 @@ impl MatrixBot {
             Self::try_bootstrap_cross_signing(&encryption).await;
         }
         
-        // Note: Backups are disabled to avoid warning messages before device verification
-        // Users can manually enable backups via Element client after verification if needed
+        // Note: Backups aren't created automatically, they are enabled or
+        // restored with the recovery key from the web interface
         info!("Encryption setup complete. Device verification recommended for full E2EE functionality.");
         info!("To complete setup:");
         info!("1. Open Element on another device where you're logged in");
         info!("2. Verify this new session");
+        info!("Or unlock the account's secret storage with its recovery key from the web interface");
         
         Ok(())
     }
@@ -319,144 +663,600 @@ impl MatrixBot {
         info!("3. Verify this new device session");
     }
 
-    async fn load_message_history_with_client(&self, client: &Client, limit: usize) -> anyhow::Result<()> {
-        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(self.room_id.as_str())?;
+    /// Fill a room's timeline cache with the latest page of its history
+    async fn load_message_history_with_client(&self, client: &Client, room_id_str: &str, limit: usize) -> anyhow::Result<()> {
+        info!("Loading message history for {} (limit: {})", room_id_str, limit);
         
-        info!("Loading message history (limit: {})", limit);
+        let page = Self::fetch_history_page(client, room_id_str, None, limit).await?;
+        info!("Loaded {} messages from history for {}", page.messages.len(), room_id_str);
+        self.archive_messages(&page.messages);
+        let mut timelines = self.timelines.write().await;
+        self.timeline_mut(&mut timelines, room_id_str).fill(page);
         
-        // Get room messages
-        let mut request = get_message_events::v3::Request::backward(room_id.to_owned());
-        request.limit = UInt::new(limit as u64).unwrap_or(UInt::new(50).unwrap());
+        Ok(())
+    }
+
+    /// Reload as much of a room's history as is cached, so every cached
+    /// message is refreshed, and tell clients to refresh theirs
+    async fn reload_history(&self, client: &Client, room_id: &str) {
+        let cached = self.timelines.read().await.get(room_id).map_or(0, |timeline| timeline.messages().len());
+        let limit = cached.max(self.history_limit).min(self.history_cache_size);
+        match self.load_message_history_with_client(client, room_id, limit).await {
+            Ok(()) => {
+                let _ = self.message_tx.send(RoomUpdate::History(HistoryReload { room_id: room_id.to_string() }));
+            }
+            Err(e) => error!("Failed to reload message history for {}: {:#}", room_id, e),
+        }
+    }
+
+    /// Watch for room keys downloaded from the key backup and reload the
+    /// history of their room, so messages that couldn't be decrypted when
+    /// they were loaded are shown
+    async fn watch_room_keys(&self, client: &Client) {
+        let mut watchers = self.room_key_watchers.lock().await;
         
-        match client.send(request, None).await {
-            Ok(response) => {
-                let mut history = Vec::new();
-                
-                // Process messages in reverse order (oldest first)
-                for event_raw in response.chunk.iter().rev() {
-                    if let Ok(matrix_sdk::ruma::events::AnyTimelineEvent::MessageLike(
-                        matrix_sdk::ruma::events::AnyMessageLikeEvent::RoomMessage(
-                            matrix_sdk::ruma::events::room::message::RoomMessageEvent::Original(msg),
-                        ),
-                    )) = event_raw.deserialize()
-                    {
-                        let sender = msg.sender.to_string();
-                        if let MessageType::Text(text) = msg.content.msgtype {
-                            let formatted_message = format!("{}: {}", sender, text.body);
-                            history.push(formatted_message);
+        for room_id in &self.room_ids {
+            let Ok(parsed_room_id) = <&matrix_sdk::ruma::RoomId>::try_from(room_id.as_str()) else {
+                continue;
+            };
+            let keys = client.encryption().backups().room_keys_for_room_stream(parsed_room_id);
+            let bot = self.clone();
+            let client = client.clone();
+            let room_id = room_id.clone();
+            
+            watchers.push(tokio::spawn(async move {
+                let mut keys = std::pin::pin!(keys);
+                while keys.next().await.is_some() {
+                    // Keys of one page arrive one by one, wait until they stop
+                    let delay = std::time::Duration::from_millis(ROOM_KEY_BATCH_DELAY_MS);
+                    while let Ok(Some(_)) = tokio::time::timeout(delay, keys.next()).await {}
+                    
+                    info!("Room keys for {} arrived from the backup, reloading history", room_id);
+                    bot.reload_history(&client, &room_id).await;
+                }
+            }));
+        }
+    }
+
+    /// The sessions of the room keys a sync response brought, sent by the
+    /// room's members or forwarded by our other devices on request
+    fn received_sessions(response: &SyncResponse) -> HashSet<String> {
+        response
+            .to_device
+            .iter()
+            .filter_map(|event| match event.deserialize() {
+                Ok(AnyToDeviceEvent::RoomKey(key)) => Some(key.content.session_id),
+                Ok(AnyToDeviceEvent::ForwardedRoomKey(key)) => Some(key.content.session_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Decrypt the placeholders of the given sessions in the timeline caches,
+    /// from the encrypted events kept with them, once their keys arrived.
+    ///
+    /// A decrypted message replaces its placeholder. A placeholder that
+    /// turns out to be an edit or a reaction can't stand alone, so the
+    /// history of its room is reloaded to apply it.
+    async fn retry_undecryptable(&self, client: &Client, sessions: &HashSet<String>) {
+        let pending: Vec<(String, String, EncryptedSource)> = {
+            let timelines = self.timelines.read().await;
+            timelines
+                .iter()
+                .flat_map(|(room_id, timeline)| {
+                    timeline.messages().iter().filter_map(move |m| {
+                        let source = m.encrypted.as_ref().filter(|source| sessions.contains(&source.session_id))?;
+                        Some((room_id.clone(), m.event_id.clone(), source.clone()))
+                    })
+                })
+                .collect()
+        };
+        
+        let mut rooms_to_reload = HashSet::new();
+        for (room_id, event_id, source) in pending {
+            let Ok(room) = Self::joined_room(client, &room_id) else {
+                continue;
+            };
+            let event = match room.decrypt_event(&source.event).await {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Failed to decrypt {} after its key arrived: {}", event_id, e);
+                    continue;
+                }
+            };
+            
+            let mut decrypted = Self::messages_from_timeline(&room, std::iter::once(&event)).await;
+            let Some(mut message) = decrypted.pop().filter(|m| m.event_id == event_id) else {
+                rooms_to_reload.insert(room_id);
+                continue;
+            };
+            
+            let mut timelines = self.timelines.write().await;
+            let timeline = self.timeline_mut(&mut timelines, &room_id);
+            let Some(placeholder) = timeline.messages_mut().iter_mut().find(|m| m.event_id == event_id) else {
+                continue;
+            };
+            info!("Decrypted {} in {} after its key arrived", event_id, room_id);
+            message.reactions = std::mem::take(&mut placeholder.reactions);
+            *placeholder = message.clone();
+            self.archive_messages(std::slice::from_ref(&message));
+            let _ = self.message_tx.send(RoomUpdate::Decrypted(message));
+        }
+        
+        for room_id in rooms_to_reload {
+            self.reload_history(client, &room_id).await;
+        }
+    }
+
+    /// Save messages to the archive, if enabled
+    fn archive_messages(&self, messages: &[MessageEvent]) {
+        if let Some(archive) = &self.archive {
+            archive.store_messages(messages);
+        }
+    }
+
+    /// The cached timeline of a room, created empty if needed
+    fn timeline_mut<'a>(&self, timelines: &'a mut HashMap<String, RoomTimeline>, room_id: &str) -> &'a mut RoomTimeline {
+        timelines
+            .entry(room_id.to_string())
+            .or_insert_with(|| RoomTimeline::new(self.history_cache_size))
+    }
+
+    /// Note the pagination token of each room's sync batch, and mark rooms
+    /// whose timeline was limited as having a gap
+    async fn record_sync_batches(&self, response: &SyncResponse) {
+        let mut timelines = self.timelines.write().await;
+        for (room_id, update) in &response.rooms.join {
+            let Some(timeline) = timelines.get_mut(room_id.as_str()) else {
+                continue;
+            };
+            
+            if update.timeline.limited {
+                info!("Sync for {} skipped events, history will be refilled", room_id);
+                timeline.mark_gap();
+            }
+            
+            let event_ids: Vec<_> = update.timeline.events.iter().filter_map(|e| e.event_id()).collect();
+            timeline.mark_batch(event_ids.iter().map(|id| id.as_str()), update.timeline.prev_batch.clone());
+        }
+    }
+
+    /// Fetch up to `limit` events of a room's history going backward from
+    /// `from`, or from the latest event when `from` is `None`.
+    ///
+    /// Encrypted events are decrypted with the keys we have. Edits and
+    /// reactions are folded into the messages they target when both are in
+    /// the same page.
+    async fn fetch_history_page(client: &Client, room_id_str: &str, from: Option<&str>, limit: usize) -> anyhow::Result<HistoryPage> {
+        let room = Self::joined_room(client, room_id_str)?;
+        
+        let mut options = MessagesOptions::backward().from(from);
+        options.limit = UInt::new(limit as u64).unwrap_or(UInt::new(50).unwrap());
+        let response = room.messages(options).await?;
+        
+        // Process events in reverse order (oldest first)
+        let messages = Self::messages_from_timeline(&room, response.chunk.iter().rev()).await;
+        
+        // An empty chunk means we reached the start of the room
+        let next_token = if response.chunk.is_empty() { None } else { response.end };
+        
+        Ok(HistoryPage { messages, next_token })
+    }
+
+    fn joined_room(client: &Client, room_id_str: &str) -> anyhow::Result<Room> {
+        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(room_id_str)?;
+        client.get_room(room_id).ok_or_else(|| anyhow::anyhow!("Room not found: {}", room_id_str))
+    }
+
+    /// Turn timeline events, oldest first, into messages, applying edits and
+    /// reactions to the earlier messages they target
+    async fn messages_from_timeline<'a>(room: &Room, events: impl Iterator<Item = &'a TimelineEvent>) -> Vec<MessageEvent> {
+        let mut messages = Vec::new();
+        
+        for timeline_event in events {
+            match timeline_event.event.deserialize() {
+                Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                    RoomMessageEvent::Original(msg),
+                ))) => {
+                    if let Some(Relation::Replacement(replacement)) = &msg.content.relates_to {
+                        // Edits come after the message they replace
+                        if let Some(edit) = MessageEvent::from_replacement(
+                            room,
+                            &msg.sender,
+                            msg.origin_server_ts,
+                            replacement,
+                        ).await {
+                            apply_edit(&mut messages, edit);
                         }
+                    } else if let Some(message) = MessageEvent::from_parts(
+                        room,
+                        &msg.event_id,
+                        &msg.sender,
+                        msg.origin_server_ts,
+                        &msg.content,
+                    ).await {
+                        messages.push(message);
                     }
                 }
-                
-                info!("Loaded {} messages from history", history.len());
-                let mut msg_history = self.message_history.write().await;
-                *msg_history = history;
+                Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomEncrypted(
+                    RoomEncryptedEvent::Original(encrypted),
+                ))) => {
+                    // Still encrypted, so we don't have its key yet
+                    let source = EncryptedSource::new(timeline_event.event.cast_ref().clone(), &encrypted.content);
+                    messages.push(MessageEvent::undecryptable(
+                        room,
+                        &encrypted.event_id,
+                        &encrypted.sender,
+                        encrypted.origin_server_ts,
+                        source,
+                    ).await);
+                }
+                Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::Reaction(
+                    ReactionEvent::Original(reaction),
+                ))) => {
+                    // Reactions also come after the message they annotate
+                    let annotation = &reaction.content.relates_to;
+                    apply_reaction(
+                        &mut messages,
+                        annotation.event_id.as_str(),
+                        &annotation.key,
+                        reaction.sender.as_str(),
+                        reaction.event_id.as_str(),
+                    );
+                }
+                _ => {}
             }
-            Err(e) => {
-                error!("Failed to load message history: {}", e);
+        }
+        
+        messages
+    }
+
+    /// Fetch a room's whole history back to `since` (milliseconds since the
+    /// epoch), or to the start of the room, oldest message first.
+    ///
+    /// All events are collected before they are turned into messages, so
+    /// edits and reactions apply even when they are far from their target.
+    pub async fn export_history(&self, room_id: &str, since: Option<u64>) -> anyhow::Result<Vec<MessageEvent>> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let room = Self::joined_room(&client, room_id)?;
+        
+        let mut events: Vec<TimelineEvent> = Vec::new();
+        let mut from: Option<String> = None;
+        loop {
+            let mut options = MessagesOptions::backward().from(from.as_deref());
+            options.limit = UInt::new(MAX_HISTORY_PAGE_SIZE as u64).unwrap_or(UInt::new(50).unwrap());
+            let response = room.messages(options).await?;
+            
+            // Pages go backward in time, so the last event is the oldest
+            let reached_since = since.is_some_and(|since| {
+                response
+                    .chunk
+                    .last()
+                    .and_then(|e| e.event.get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts").ok().flatten())
+                    .is_some_and(|ts| u64::from(ts.get()) < since)
+            });
+            let at_start = response.chunk.is_empty() || response.end.is_none();
+            
+            events.extend(response.chunk);
+            info!("Fetched {} events of {} for export", events.len(), room_id);
+            
+            if reached_since || at_start {
+                break;
             }
+            from = response.end;
         }
         
-        Ok(())
+        let messages = Self::messages_from_timeline(&room, events.iter().rev()).await;
+        self.archive_messages(&messages);
+        Ok(messages)
     }
 
     async fn start_sync_with_client(&self, client: Client) {
         let bot_for_sync = self.clone();
-        let room_id = self.room_id.clone();
         
         let handle = tokio::spawn(async move {
             // Register event handler for incoming messages
+            let bot_for_messages = bot_for_sync.clone();
             client.add_event_handler(
                 move |event: OriginalSyncRoomMessageEvent, room: Room| {
-                    let bot = bot_for_sync.clone();
-                    let room_id_clone = room_id.clone();
+                    let bot = bot_for_messages.clone();
                     async move {
-                        if room.room_id().as_str() != room_id_clone {
+                        let room_id = room.room_id().to_string();
+                        if !bot.has_room(&room_id) {
+                            return;
+                        }
+
+                        if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+                            let Some(edit) = MessageEvent::from_replacement(
+                                &room,
+                                &event.sender,
+                                event.origin_server_ts,
+                                replacement,
+                            ).await else {
+                                return;
+                            };
+
+                            let mut timelines = bot.timelines.write().await;
+                            let timeline = bot.timeline_mut(&mut timelines, &room_id);
+                            if let Some(message) = apply_edit(timeline.messages_mut(), edit) {
+                                info!("Message {} in {} was edited", message.event_id, room_id);
+                                bot.archive_messages(std::slice::from_ref(&message));
+                                let _ = bot.message_tx.send(RoomUpdate::Edit(message));
+                            }
                             return;
                         }
 
-                        let sender = event.sender.to_string();
-                        let message = match event.content.msgtype {
-                            MessageType::Text(text) => text.body.clone(),
-                            _ => return,
+                        let Some(message) = MessageEvent::from_parts(
+                            &room,
+                            &event.event_id,
+                            &event.sender,
+                            event.origin_server_ts,
+                            &event.content,
+                        ).await else {
+                            return;
                         };
 
-                        let formatted_message = format!("{}: {}", sender, message);
-                        info!("Received message: {}", formatted_message);
+                        info!("Received {} from {} in {}", message.msgtype, message.sender, room_id);
                         
-                        // Add to history
-                        let mut history = bot.message_history.write().await;
-                        history.push(formatted_message.clone());
+                        // Add to the timeline cache, skipping events we already have
+                        let mut timelines = bot.timelines.write().await;
+                        if !bot.timeline_mut(&mut timelines, &room_id).push(message.clone()) {
+                            return;
+                        }
+                        bot.archive_messages(std::slice::from_ref(&message));
                         
                         // Broadcast to web clients
-                        let _ = bot.message_tx.send(formatted_message);
+                        let _ = bot.message_tx.send(RoomUpdate::Message(message));
                     }
                 },
             );
 
-            info!("Starting sync loop");
-            // Use sync_with_result_callback instead of sync() to handle transient
-            // errors gracefully. client.sync() stops the entire loop on any error,
-            // which means device list changes, key queries, and key distribution
-            // all stop — breaking E2E encryption for new devices.
-            if let Err(e) = client.sync_with_result_callback(SyncSettings::default(), |result| async move {
-                if let Err(ref e) = result {
-                    error!("Sync error (will retry): {}", e);
-                }
-                // Always continue the sync loop regardless of errors
-                Ok(LoopCtrl::Continue)
-            }).await {
-                error!("Sync loop terminated unexpectedly: {}", e);
-            }
-        });
-        
-        *self.sync_handle.lock().await = Some(handle);
-    }
-
-    pub async fn get_message_history(&self) -> Vec<String> {
-        let client = self.client.lock().await.clone();
-        if let Some(client) = client {
-            if let Err(e) = self.load_message_history_with_client(&client, self.history_limit).await {
-                warn!("Failed to refresh message history: {}", e);
-            }
-        }
+            let bot_for_undecryptable = bot_for_sync.clone();
+            client.add_event_handler(
+                move |raw: Raw<OriginalSyncRoomEncryptedEvent>, room: Room| {
+                    let bot = bot_for_undecryptable.clone();
+                    async move {
+                        let room_id = room.room_id().to_string();
+                        if !bot.has_room(&room_id) {
+                            return;
+                        }
+                        let Ok(event) = raw.deserialize() else {
+                            return;
+                        };
 
-        let history = self.message_history.read().await;
-        history.clone()
-    }
+                        // Only events that couldn't be decrypted arrive here
+                        // encrypted; their key was requested from our other devices
+                        warn!("Unable to decrypt {} from {} in {}, waiting for its key", event.event_id, event.sender, room_id);
+                        let message = MessageEvent::undecryptable(
+                            &room,
+                            &event.event_id,
+                            &event.sender,
+                            event.origin_server_ts,
+                            EncryptedSource::new(raw, &event.content),
+                        ).await;
 
-    pub async fn send_message(&self, message: &str) -> anyhow::Result<()> {
-        let client_guard = self.client.lock().await;
-        let client = client_guard.as_ref().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+                        let mut timelines = bot.timelines.write().await;
+                        if !bot.timeline_mut(&mut timelines, &room_id).push(message.clone()) {
+                            return;
+                        }
+                        let _ = bot.message_tx.send(RoomUpdate::Message(message));
+                    }
+                },
+            );
+
+            let bot_for_reactions = bot_for_sync.clone();
+            client.add_event_handler(
+                move |event: OriginalSyncReactionEvent, room: Room| {
+                    let bot = bot_for_reactions.clone();
+                    async move {
+                        let room_id = room.room_id().to_string();
+                        if !bot.has_room(&room_id) {
+                            return;
+                        }
+
+                        let annotation = &event.content.relates_to;
+                        let mut timelines = bot.timelines.write().await;
+                        let timeline = bot.timeline_mut(&mut timelines, &room_id);
+                        if let Some(message) = apply_reaction(
+                            timeline.messages_mut(),
+                            annotation.event_id.as_str(),
+                            &annotation.key,
+                            event.sender.as_str(),
+                            event.event_id.as_str(),
+                        ) {
+                            info!("{} reacted with {} to {}", event.sender, annotation.key, annotation.event_id);
+                            bot.archive_messages(std::slice::from_ref(&message));
+                            let _ = bot.message_tx.send(RoomUpdate::Reactions(ReactionUpdate::from(&message)));
+                        }
+                    }
+                },
+            );
+
+            let bot_for_redactions = bot_for_sync.clone();
+            client.add_event_handler(
+                move |event: OriginalSyncRoomRedactionEvent, room: Room| {
+                    let bot = bot_for_redactions.clone();
+                    async move {
+                        let room_id = room.room_id().to_string();
+                        if !bot.has_room(&room_id) {
+                            return;
+                        }
+
+                        // Room v11 moved `redacts` into the content
+                        let Some(redacted) = event.content.redacts.as_ref().or(event.redacts.as_ref()) else {
+                            return;
+                        };
+
+                        info!("Event {} in {} was redacted by {}", redacted, room_id, event.sender);
+                        
+                        let mut timelines = bot.timelines.write().await;
+                        let timeline = bot.timeline_mut(&mut timelines, &room_id);
+                        
+                        // A redacted reaction only changes the message it was on
+                        if let Some(message) = remove_reaction(timeline.messages_mut(), redacted.as_str()) {
+                            bot.archive_messages(std::slice::from_ref(&message));
+                            let _ = bot.message_tx.send(RoomUpdate::Reactions(ReactionUpdate::from(&message)));
+                            return;
+                        }
+                        
+                        timeline.remove(redacted.as_str());
+                        if let Some(archive) = &bot.archive {
+                            archive.remove_message(redacted.as_str());
+                        }
+                        let _ = bot.message_tx.send(RoomUpdate::Redaction(Redaction {
+                            room_id,
+                            event_id: redacted.to_string(),
+                            reason: event.content.reason.clone(),
+                        }));
+                    }
+                },
+            );
+
+            info!("Starting sync loop");
+            // Use sync_with_result_callback instead of sync() to handle transient
+            // errors gracefully. client.sync() stops the entire loop on any error,
+            // which means device list changes, key queries, and key distribution
+            // all stop — breaking E2E encryption for new devices.
+            let client_for_keys = client.clone();
+            if let Err(e) = client.sync_with_result_callback(SyncSettings::default(), |result| {
+                let bot = bot_for_sync.clone();
+                let client = client_for_keys.clone();
+                async move {
+                    match result {
+                        Ok(response) => {
+                            bot.record_sync_batches(&response).await;
+                            let sessions = Self::received_sessions(&response);
+                            if !sessions.is_empty() {
+                                // Don't hold up the sync loop while decrypting
+                                tokio::spawn(async move {
+                                    bot.retry_undecryptable(&client, &sessions).await;
+                                });
+                            }
+                        }
+                        Err(e) => error!("Sync error (will retry): {}", e),
+                    }
+                    // Always continue the sync loop regardless of errors
+                    Ok(LoopCtrl::Continue)
+                }
+            }).await {
+                error!("Sync loop terminated unexpectedly: {}", e);
+            }
+        });
         
-        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(self.room_id.as_str())?;
+        *self.sync_handle.lock().await = Some(handle);
+    }
+
+    /// Get a page of a room's history.
+    ///
+    /// Without `from`, the latest messages are served from the timeline cache,
+    /// which is only refilled from the homeserver when it has a gap. Tokens
+    /// from cached pages continue through the cache, and homeserver tokens
+    /// fetch older pages directly.
+    pub async fn get_message_history(&self, room_id: &str, from: Option<&str>, limit: Option<usize>) -> anyhow::Result<HistoryPage> {
+        let limit = limit.unwrap_or(self.history_limit).clamp(1, MAX_HISTORY_PAGE_SIZE);
         
-        if let Some(room) = client.get_room(room_id) {
-            // Ensure all room members are loaded and their device keys are tracked.
-            // This is critical for E2E encryption: when a user adds a new device,
-            // we need to share the room key with that device. The SDK's send flow
-            // calls sync_members() only once and then relies on the sync loop for
-            // device updates. By explicitly loading members here, we ensure the
-            // SDK's device tracking is aware of all current room members.
-            if let Err(e) = room.members(RoomMemberships::ACTIVE).await {
-                warn!("Failed to sync room members before send: {}", e);
+        if let Some(from) = from {
+            if let Some(event_id) = cache_token_event(from) {
+                let timelines = self.timelines.read().await;
+                return timelines
+                    .get(room_id)
+                    .and_then(|timeline| timeline.page(Some(event_id), limit))
+                    .ok_or_else(|| anyhow::anyhow!("Pagination token has expired, reload the history"));
             }
             
-            // Ensure we have up-to-date device information for all room members.
-            // This reads from the local crypto store, ensuring the SDK's encryption
-            // layer is aware of all known devices (including recently added ones
-            // that the sync loop has picked up).
-            if let Ok(members) = room.members_no_sync(RoomMemberships::ACTIVE).await {
-                for member in &members {
-                    if let Err(e) = client.encryption().get_user_devices(member.user_id()).await {
-                        warn!("Failed to get devices for {}: {}", member.user_id(), e);
+            let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+            let page = Self::fetch_history_page(&client, room_id, Some(from), limit).await?;
+            self.archive_messages(&page.messages);
+            return Ok(page);
+        }
+        
+        let has_gap = self.timelines.read().await.get(room_id).is_none_or(RoomTimeline::has_gap);
+        if has_gap {
+            let client = self.client.lock().await.clone();
+            if let Some(client) = client {
+                // A page larger than the cache would be trimmed away entirely
+                let fill_limit = limit.max(self.history_limit).min(self.history_cache_size);
+                self.load_message_history_with_client(&client, room_id, fill_limit).await?;
+            }
+        }
+
+        let timelines = self.timelines.read().await;
+        Ok(timelines
+            .get(room_id)
+            .and_then(|timeline| timeline.page(None, limit))
+            .unwrap_or(HistoryPage {
+                messages: Vec::new(),
+                next_token: None,
+            }))
+    }
+
+    /// List the configured rooms, with display names once connected
+    pub async fn get_rooms(&self) -> Vec<RoomInfo> {
+        let client = self.client.lock().await.clone();
+        let mut rooms = Vec::with_capacity(self.room_ids.len());
+        
+        for room_id in &self.room_ids {
+            let mut name = None;
+            if let Some(ref client) = client {
+                if let Ok(parsed_room_id) = <&matrix_sdk::ruma::RoomId>::try_from(room_id.as_str()) {
+                    if let Some(room) = client.get_room(parsed_room_id) {
+                        match room.display_name().await {
+                            Ok(display_name) => name = Some(display_name.to_string()),
+                            Err(e) => warn!("Failed to compute display name for {}: {}", room_id, e),
+                        }
                     }
                 }
             }
+            rooms.push(RoomInfo {
+                room_id: room_id.clone(),
+                name,
+            });
+        }
+        
+        rooms
+    }
+
+    /// Send a text message, optionally rendered from Markdown and sent as a
+    /// reply and/or into a thread.
+    ///
+    /// Replies get a rich reply fallback built from the replied-to event. A
+    /// thread message without a reply target falls back to the latest known
+    /// message in the thread, as the spec recommends.
+    pub async fn send_message(&self, room_id: &str, message: &str, options: MessageOptions<'_>) -> anyhow::Result<()> {
+        let client_guard = self.client.lock().await;
+        let client = client_guard.as_ref().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        
+        let room_id_str = room_id;
+        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(room_id)?;
+        
+        if let Some(room) = client.get_room(room_id) {
+            Self::prepare_room_for_send(client, &room).await;
+            
+            let mut content = if options.markdown {
+                RoomMessageEventContent::text_markdown(message)
+            } else {
+                RoomMessageEventContent::text_plain(message)
+            };
+            
+            match (options.thread_root_event_id, options.reply_to_event_id) {
+                (Some(_), Some(reply_to)) => {
+                    let replied_to = Self::fetch_original_message(&room, reply_to).await?;
+                    content = content.make_for_thread(&replied_to, ReplyWithinThread::Yes, AddMentions::Yes);
+                }
+                (Some(thread_root), None) => {
+                    let latest = self.latest_thread_event(room_id_str, thread_root).await;
+                    let previous = Self::fetch_original_message(&room, &latest).await?;
+                    content = content.make_for_thread(&previous, ReplyWithinThread::No, AddMentions::No);
+                }
+                (None, Some(reply_to)) => {
+                    let replied_to = Self::fetch_original_message(&room, reply_to).await?;
+                    content = content.make_reply_to(&replied_to, ForwardThread::Yes, AddMentions::Yes);
+                }
+                (None, None) => {}
+            }
             
-            let content = RoomMessageEventContent::text_plain(message);
             room.send(content).await?;
-            info!("Sent message to room");
+            info!("Sent message to room {}", room_id);
         } else {
             error!("Room not found");
             anyhow::bail!("Room not found");
@@ -465,6 +1265,353 @@ impl MatrixBot {
         Ok(())
     }
 
+    /// Fetch a message event from the server, decrypting it if needed
+    async fn fetch_original_message(room: &Room, event_id: &str) -> anyhow::Result<OriginalRoomMessageEvent> {
+        let parsed_event_id = <&EventId>::try_from(event_id)?;
+        let timeline_event = room.event(parsed_event_id).await
+            .with_context(|| format!("Failed to fetch event {}", event_id))?;
+        
+        match timeline_event.event.deserialize()? {
+            AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(RoomMessageEvent::Original(msg))) => Ok(msg),
+            _ => anyhow::bail!("Event {} is not a room message", event_id),
+        }
+    }
+
+    /// The most recent cached message in a thread, or the root if none is cached
+    async fn latest_thread_event(&self, room_id: &str, thread_root: &str) -> String {
+        let timelines = self.timelines.read().await;
+        timelines
+            .get(room_id)
+            .and_then(|timeline| {
+                timeline
+                    .messages()
+                    .iter()
+                    .rev()
+                    .find(|m| m.thread_root.as_deref() == Some(thread_root))
+            })
+            .map(|m| m.event_id.clone())
+            .unwrap_or_else(|| thread_root.to_string())
+    }
+
+    /// Upload a file to the media repository and send it as an `m.image`,
+    /// `m.video`, `m.audio` or `m.file` message depending on its content type.
+    ///
+    /// The SDK encrypts the upload when the room is encrypted.
+    pub async fn send_attachment(
+        &self,
+        room_id: &str,
+        filename: &str,
+        content_type: &mime::Mime,
+        data: Vec<u8>,
+        dimensions: AttachmentDimensions,
+    ) -> anyhow::Result<()> {
+        // Don't hold the lock for the whole upload, other requests need the client too
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        
+        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(room_id)?;
+        let room = client.get_room(room_id).ok_or_else(|| anyhow::anyhow!("Room not found"))?;
+        
+        Self::prepare_room_for_send(&client, &room).await;
+        
+        let size = UInt::new(data.len() as u64);
+        let width = dimensions.width.map(UInt::from);
+        let height = dimensions.height.map(UInt::from);
+        let duration = dimensions.duration_ms.map(std::time::Duration::from_millis);
+        
+        let info = match content_type.type_() {
+            mime::IMAGE => AttachmentInfo::Image(BaseImageInfo {
+                height,
+                width,
+                size,
+                blurhash: None,
+            }),
+            mime::VIDEO => AttachmentInfo::Video(BaseVideoInfo {
+                duration,
+                height,
+                width,
+                size,
+                blurhash: None,
+            }),
+            mime::AUDIO => AttachmentInfo::Audio(BaseAudioInfo { duration, size }),
+            _ => AttachmentInfo::File(BaseFileInfo { size }),
+        };
+        
+        room.send_attachment(filename, content_type, data, AttachmentConfig::new().info(info)).await?;
+        info!("Sent attachment {} ({}) to room {}", filename, content_type, room_id);
+        
+        Ok(())
+    }
+
+    /// Ensure all room members are loaded and their device keys are tracked
+    /// before sending an event.
+    async fn prepare_room_for_send(client: &Client, room: &Room) {
+        // This is critical for E2E encryption: when a user adds a new device,
+        // we need to share the room key with that device. The SDK's send flow
+        // calls sync_members() only once and then relies on the sync loop for
+        // device updates. By explicitly loading members here, we ensure the
+        // SDK's device tracking is aware of all current room members.
+        if let Err(e) = room.members(RoomMemberships::ACTIVE).await {
+            warn!("Failed to sync room members before send: {}", e);
+        }
+        
+        // Ensure we have up-to-date device information for all room members.
+        // This reads from the local crypto store, ensuring the SDK's encryption
+        // layer is aware of all known devices (including recently added ones
+        // that the sync loop has picked up).
+        if let Ok(members) = room.members_no_sync(RoomMemberships::ACTIVE).await {
+            for member in &members {
+                if let Err(e) = client.encryption().get_user_devices(member.user_id()).await {
+                    warn!("Failed to get devices for {}: {}", member.user_id(), e);
+                }
+            }
+        }
+    }
+
+    /// Find a room message by event ID, along with the room it was sent in.
+    ///
+    /// Rooms whose cached history contains the event are tried first, then the
+    /// remaining configured rooms.
+    async fn find_message(&self, client: &Client, event_id: &EventId) -> Result<(Room, OriginalRoomMessageEvent), MessageError> {
+        let cached_room_id = {
+            let timelines = self.timelines.read().await;
+            timelines
+                .iter()
+                .find(|(_, timeline)| timeline.messages().iter().any(|m| m.event_id == event_id.as_str()))
+                .map(|(room_id, _)| room_id.clone())
+        };
+        
+        let candidates = cached_room_id
+            .iter()
+            .chain(self.room_ids.iter().filter(|r| Some(*r) != cached_room_id.as_ref()));
+        
+        for room_id in candidates {
+            let Ok(parsed_room_id) = <&matrix_sdk::ruma::RoomId>::try_from(room_id.as_str()) else {
+                continue;
+            };
+            let Some(room) = client.get_room(parsed_room_id) else {
+                continue;
+            };
+            
+            match room.event(event_id).await {
+                Ok(timeline_event) => {
+                    if let Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                        RoomMessageEvent::Original(msg),
+                    ))) = timeline_event.event.deserialize()
+                    {
+                        return Ok((room, msg));
+                    }
+                    return Err(MessageError::NotRoomMessage(event_id.to_string()));
+                }
+                Err(e) => {
+                    info!("Event {} not found in {}: {}", event_id, room_id, e);
+                }
+            }
+        }
+        
+        Err(MessageError::NotFound(event_id.to_string()))
+    }
+
+    /// Find a message sent by the bot account
+    async fn find_own_message(&self, client: &Client, event_id: &str) -> anyhow::Result<(Room, OriginalRoomMessageEvent)> {
+        let parsed_event_id = <&EventId>::try_from(event_id)?;
+        let (room, message) = self.find_message(client, parsed_event_id).await?;
+        
+        if client.user_id() != Some(&*message.sender) {
+            return Err(MessageError::NotOwnMessage(event_id.to_string()).into());
+        }
+        
+        Ok((room, message))
+    }
+
+    /// Replace the text of a message the bot sent with an `m.replace` edit
+    pub async fn edit_message(&self, event_id: &str, message: &str, markdown: bool) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let (room, original) = self.find_own_message(&client, event_id).await?;
+        
+        if !matches!(original.content.msgtype, MessageType::Text(_) | MessageType::Notice(_) | MessageType::Emote(_)) {
+            return Err(MessageError::CannotEdit(event_id.to_string()).into());
+        }
+        
+        Self::prepare_room_for_send(&client, &room).await;
+        
+        let content = if markdown {
+            RoomMessageEventContent::text_markdown(message)
+        } else {
+            RoomMessageEventContent::text_plain(message)
+        };
+        let content = content.make_replacement(ReplacementMetadata::from(&original), None);
+        
+        room.send(content).await?;
+        info!("Edited message {} in room {}", event_id, room.room_id());
+        
+        Ok(())
+    }
+
+    /// Redact a message the bot sent
+    pub async fn redact_message(&self, event_id: &str, reason: Option<&str>) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let (room, original) = self.find_own_message(&client, event_id).await?;
+        
+        room.redact(&original.event_id, reason, None).await?;
+        info!("Redacted message {} in room {}", event_id, room.room_id());
+        
+        Ok(())
+    }
+
+    /// React to a message from the bot account. Reacting twice with the same
+    /// key is a no-op.
+    pub async fn add_reaction(&self, event_id: &str, key: &str) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let user_id = client.user_id().ok_or_else(|| anyhow::anyhow!("Not logged in"))?.to_string();
+        
+        if self.cached_reaction(event_id, key, &user_id).await.is_some() {
+            return Ok(());
+        }
+        
+        let parsed_event_id = <&EventId>::try_from(event_id)?;
+        let (room, _) = self.find_message(&client, parsed_event_id).await?;
+        
+        Self::prepare_room_for_send(&client, &room).await;
+        
+        let content = ReactionEventContent::new(Annotation::new(parsed_event_id.to_owned(), key.to_string()));
+        room.send(content).await?;
+        info!("Reacted with {} to {} in room {}", key, event_id, room.room_id());
+        
+        Ok(())
+    }
+
+    /// Take back a reaction the bot account made, by redacting it
+    pub async fn remove_reaction(&self, event_id: &str, key: &str) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let user_id = client.user_id().ok_or_else(|| anyhow::anyhow!("Not logged in"))?.to_string();
+        
+        let (room_id, reaction_event_id) = self
+            .cached_reaction(event_id, key, &user_id)
+            .await
+            .ok_or_else(|| MessageError::ReactionNotFound {
+                event_id: event_id.to_string(),
+                key: key.to_string(),
+            })?;
+        
+        let room = client
+            .get_room(<&matrix_sdk::ruma::RoomId>::try_from(room_id.as_str())?)
+            .ok_or_else(|| anyhow::anyhow!("Room not found"))?;
+        room.redact(<&EventId>::try_from(reaction_event_id.as_str())?, None, None).await?;
+        info!("Removed {} reaction from {} in room {}", key, event_id, room_id);
+        
+        Ok(())
+    }
+
+    /// The room and event ID of `user_id`'s `key` reaction to a cached message
+    async fn cached_reaction(&self, event_id: &str, key: &str, user_id: &str) -> Option<(String, String)> {
+        let timelines = self.timelines.read().await;
+        timelines.iter().find_map(|(room_id, timeline)| {
+            timeline
+                .messages()
+                .iter()
+                .find(|m| m.event_id == event_id)
+                .and_then(|m| m.reaction_by(key, user_id))
+                .map(|reaction| (room_id.clone(), reaction.to_string()))
+        })
+    }
+
+    /// The bot account's user ID, once connected
+    pub async fn user_id(&self) -> Option<String> {
+        let client = self.client.lock().await;
+        client.as_ref().and_then(|c| c.user_id()).map(|u| u.to_string())
+    }
+    
+    /// State of the account's secret storage for this device
+    pub async fn secret_storage_status(&self) -> anyhow::Result<SecretStorageStatus> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        recovery::secret_storage_status(&client).await
+    }
+    
+    /// Set up secret storage, returning its new recovery key
+    pub async fn set_up_secret_storage(&self, passphrase: Option<&str>) -> anyhow::Result<String> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        recovery::set_up_secret_storage(&client, passphrase).await
+    }
+    
+    /// Import the cross-signing and backup keys from secret storage
+    pub async fn unlock_secret_storage(&self, recovery_key: &str) -> anyhow::Result<SecretStorageStatus> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        recovery::unlock_secret_storage(&client, recovery_key).await?;
+        recovery::secret_storage_status(&client).await
+    }
+    
+    /// State of the server-side key backup
+    pub async fn backup_status(&self) -> anyhow::Result<BackupStatus> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        recovery::backup_status(&client).await
+    }
+    
+    /// Create a key backup and start uploading room keys to it. Returns the
+    /// new recovery key too if secret storage had to be re-created to hold
+    /// the backup key.
+    pub async fn enable_backup(
+        &self,
+        recovery_key: Option<&str>,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<(BackupStatus, Option<String>)> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let new_recovery_key = recovery::enable_backup(&client, recovery_key, passphrase).await?;
+        Ok((recovery::backup_status(&client).await?, new_recovery_key))
+    }
+    
+    /// Restore the room keys of the bot's rooms from the key backup, using
+    /// the recovery key of secret storage. The history of each room is
+    /// reloaded as its keys arrive.
+    pub async fn restore_backup(&self, recovery_key: &str) -> anyhow::Result<BackupStatus> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        recovery::restore_backup(&client, recovery_key, &self.room_ids).await?;
+        recovery::backup_status(&client).await
+    }
+
+    /// Download and decrypt the attachment of a message, or its thumbnail.
+    ///
+    /// Falls back to the full file when a thumbnail is requested but the
+    /// message doesn't have one.
+    pub async fn download_media(&self, event_id: &str, thumbnail: bool) -> anyhow::Result<MediaDownload> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let event_id = <&EventId>::try_from(event_id)?;
+        
+        let (_, message) = self.find_message(&client, event_id).await.map_err(|e| match e {
+            MessageError::NotFound(id) => MediaError::NotFound(id),
+            other => MediaError::NotMedia(other.to_string()),
+        })?;
+        let sources = MediaSources::from_msgtype(&message.content.msgtype)
+            .ok_or_else(|| MediaError::NotMedia(event_id.to_string()))?;
+        
+        let (source, content_type, expected_size) = match sources.thumbnail_source {
+            Some(thumbnail_source) if thumbnail => (
+                thumbnail_source,
+                sources.thumbnail_mimetype.unwrap_or_else(|| "image/jpeg".to_string()),
+                None,
+            ),
+            _ => (
+                sources.source,
+                sources.mimetype.unwrap_or_else(|| "application/octet-stream".to_string()),
+                sources.size,
+            ),
+        };
+        
+        // Reject oversized attachments before downloading when the event tells us the size
+        if let Some(size) = expected_size {
+            if size > self.media_max_size {
+                return Err(MediaError::TooLarge { size, limit: self.media_max_size }.into());
+            }
+        }
+        
+        let body = media::download(&client, &self.http, &source, self.media_max_size).await?;
+        
+        Ok(MediaDownload {
+            body,
+            content_type,
+            filename: sources.filename,
+        })
+    }
+
     pub fn subscribe(&self) -> MessageReceiver {
         self.message_tx.subscribe()
     }
@@ -478,329 +1625,421 @@ impl MatrixBot {
         self.active_sas.read().await.clone()
     }
 
-    pub async fn accept_verification(&self, request_id: &str, other_user_id: &str) -> anyhow::Result<()> {
-        let client_guard = self.client.lock().await;
-        let client = client_guard.as_ref().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+    /// Receive every state change of the tracked verifications
+    pub fn subscribe_verifications(&self) -> VerificationReceiver {
+        self.verification_tx.subscribe()
+    }
+
+    /// Ask a user, or one of their devices, to verify the bot. The request
+    /// is tracked with the incoming ones until it is done or cancelled.
+    pub async fn request_verification(&self, user_id: &str, device_id: Option<&str>) -> anyhow::Result<VerificationRequestInfo> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let request = verification::request_verification(&client, user_id, device_id).await?;
         
+        let request_info = VerificationRequestInfo {
+            request_id: request.flow_id().to_string(),
+            other_user_id: request.other_user_id().to_string(),
+            other_device_id: device_id.unwrap_or_default().to_string(),
+            status: "requested".to_string(),
+            outgoing: true,
+            sas: None,
+            qr_show: false,
+            qr_scan: false,
+            cancel_reason: None,
+        };
+        self.track_verification(request_info.clone(), request).await;
+        
+        Ok(request_info)
+    }
+
+    /// Accept an incoming verification request. The other side starts emoji
+    /// verification next, which the request's watcher accepts.
+    pub async fn accept_verification(&self, request_id: &str, other_user_id: &str) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
         let user_id = <&UserId>::try_from(other_user_id)?;
+        let encryption = client.encryption();
         
-        // Try to get the verification request with retries (in case SDK is still processing)
-        for attempt in 0..5 {
-            if attempt > 0 {
-                info!("Retry attempt {} to get verification request", attempt);
-                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-            }
-            
-            // Get the verification request
-            if let Some(request) = client.encryption().get_verification_request(user_id, request_id).await {
-                info!("Accepting verification request: {}", request_id);
-                request.accept().await?;
-                
-                // After accepting the request, wait for it to transition to SAS verification
-                // and accept the SAS verification to start the emoji/decimal generation
-                info!("Waiting for verification request to transition to SAS...");
-                for sas_attempt in 0..MAX_SAS_TRANSITION_ATTEMPTS {
-                    // Check immediately on first attempt, then wait before subsequent attempts
-                    if sas_attempt > 0 {
-                        tokio::time::sleep(tokio::time::Duration::from_millis(SAS_TRANSITION_RETRY_DELAY_MS)).await;
-                    }
-                    
-                    info!("Checking for SAS verification (attempt {}/{})", sas_attempt + 1, MAX_SAS_TRANSITION_ATTEMPTS);
-                    
-                    if let Some(verification) = client.encryption().get_verification(user_id, request_id).await {
-                        if let Verification::SasV1(sas) = verification {
-                            info!("Verification transitioned to SAS, accepting it");
-                            match sas.accept().await {
-                                Ok(_) => {
-                                    info!("Successfully accepted SAS verification, emojis should be available soon");
-                                    return Ok(());
-                                }
-                                Err(e) => {
-                                    // Note: Error message matching is fragile but necessary since matrix-sdk
-                                    // doesn't provide specific error types for this case. This is a known
-                                    // limitation and acceptable given the alternatives.
-                                    let err_str = e.to_string();
-                                    if err_str.contains("already") || err_str.contains("accepted") {
-                                        info!("SAS verification was already accepted");
-                                        return Ok(());
-                                    } else {
-                                        warn!("Failed to accept SAS verification: {}", e);
-                                        // Continue retrying in case it's a transient error
-                                    }
-                                }
-                            }
-                        }
-                    }
+        match encryption.get_verification(user_id, request_id).await {
+            Some(Verification::SasV1(sas)) => {
+                // The other side skipped the request and started emoji verification
+                if !sas.we_started() && matches!(sas.state(), SasState::Started { .. }) {
+                    info!("Accepting SAS verification: {}", request_id);
+                    sas.accept().await?;
                 }
-                
-                warn!("Verification request accepted but SAS did not become available in time");
                 return Ok(());
             }
-            
-            // The verification request might have already transitioned to a verification flow
-            // Check if we can find it as a verification instead
-            if let Some(verification) = client.encryption().get_verification(user_id, request_id).await {
-                info!("Verification request already transitioned to verification flow");
-                if let Verification::SasV1(sas) = verification {
-                    // If it's already in SAS mode and can be presented, it still needs to be accepted
-                    if sas.can_be_presented() {
-                        info!("SAS verification is ready for presentation, accepting it");
-                        match sas.accept().await {
-                            Ok(_) => {
-                                info!("Successfully accepted SAS verification");
-                                return Ok(());
-                            }
-                            Err(e) => {
-                                // Check if the error is benign (already accepted)
-                                let err_str = e.to_string();
-                                if err_str.contains("already") || err_str.contains("accepted") {
-                                    info!("SAS verification was already accepted");
-                                    return Ok(());
-                                } else {
-                                    warn!("Failed to accept SAS verification: {}", e);
-                                    return Err(e.into());
-                                }
-                            }
-                        }
-                    }
-                    // If it's in another state, try to accept it anyway
-                    info!("Attempting to accept SAS verification");
-                    match sas.accept().await {
-                        Ok(_) => {
-                            info!("Successfully accepted SAS verification");
-                            return Ok(());
-                        }
-                        Err(e) => {
-                            let err_str = e.to_string();
-                            if err_str.contains("already") || err_str.contains("accepted") || err_str.contains("state") {
-                                info!("SAS verification might already be accepted or in different state: {}", e);
-                                return Ok(());
-                            } else {
-                                warn!("Failed to accept SAS verification: {}", e);
-                                return Err(e.into());
-                            }
-                        }
-                    }
-                } else {
-                    warn!("Verification is not SasV1 type, other verification types not currently supported");
-                    return Err(anyhow::anyhow!("Unsupported verification type"));
-                }
-            }
+            // A QR code was shown or scanned, so the request is accepted already
+            Some(_) => return Ok(()),
+            None => {}
         }
         
-        Err(anyhow::anyhow!("Verification request not found after retries"))
+        let request = encryption.get_verification_request(user_id, request_id).await.ok_or_else(|| {
+            VerificationError::NotFound(format!("Unknown verification request {}", request_id))
+        })?;
+        info!("Accepting verification request: {}", request_id);
+        request.accept_with_methods(verification::SUPPORTED_METHODS.to_vec()).await?;
+        
+        Ok(())
     }
 
+    /// Confirm that the emoji or numbers match, or that the other side
+    /// scanned the bot's QR code. The verification is done once the other side
+    /// confirmed as well, which arrives as a state change.
     pub async fn confirm_verification(&self, request_id: &str, other_user_id: &str) -> anyhow::Result<()> {
-        let client_guard = self.client.lock().await;
-        let client = client_guard.as_ref().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
-        
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
         let user_id = <&UserId>::try_from(other_user_id)?;
         
-        // Try to get the verification with retries (in case SDK is still processing)
-        for attempt in 0..5 {
-            if attempt > 0 {
-                info!("Retry attempt {} to get SAS verification", attempt);
-                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-            }
-            
-            // Get the verification
-            if let Some(Verification::SasV1(sas)) = client.encryption().get_verification(user_id, request_id).await {
+        match client.encryption().get_verification(user_id, request_id).await {
+            Some(Verification::SasV1(sas)) => {
                 info!("Confirming SAS verification");
                 sas.confirm().await?;
-                
-                // Wait for verification to complete with retries
-                for retry in 0..MAX_VERIFICATION_DONE_RETRIES {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(VERIFICATION_DONE_RETRY_DELAY_MS)).await;
-                    if sas.is_done() {
-                        info!("Verification completed successfully!");
-                        break;
-                    }
-                    if retry == MAX_VERIFICATION_DONE_RETRIES - 1 {
-                        warn!("Verification confirm sent but is_done() not yet true after {} retries", MAX_VERIFICATION_DONE_RETRIES);
-                    }
-                }
-                
-                // Always clean up after confirmation, regardless of is_done() state.
-                // This prevents the verification from being re-prompted to the user.
-                *self.active_sas.write().await = None;
-                self.verification_requests.write().await.retain(|r| r.request_id != request_id);
-                
-                return Ok(());
+            }
+            Some(Verification::QrV1(qr)) => {
+                info!("Confirming that {} scanned the QR code", other_user_id);
+                qr.confirm().await?;
+            }
+            _ => {
+                return Err(VerificationError::NotFound(format!("Nothing to confirm for request {}", request_id)).into());
             }
         }
         
-        Err(anyhow::anyhow!("SAS verification not found after retries"))
+        Ok(())
     }
 
-    pub async fn cancel_verification(&self, request_id: &str, other_user_id: &str) -> anyhow::Result<()> {
-        let client_guard = self.client.lock().await;
-        let client = client_guard.as_ref().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+    /// The QR code for the other side to scan, as an SVG image
+    pub async fn verification_qr_code(&self, request_id: &str, other_user_id: &str) -> anyhow::Result<String> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let user_id = <&UserId>::try_from(other_user_id)?;
         
+        let qr = verification::qr_code(&client, user_id, request_id).await?;
+        verification::qr_code_svg(&qr)
+    }
+
+    /// Verify the other side with a picture of the QR code it shows
+    pub async fn scan_verification_qr_code(&self, request_id: &str, other_user_id: &str, image: Vec<u8>) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
         let user_id = <&UserId>::try_from(other_user_id)?;
         
-        // Try to cancel the verification request
-        if let Some(request) = client.encryption().get_verification_request(user_id, request_id).await {
+        // Finding the code in a photo takes a while, keep it off the async workers
+        let data = tokio::task::spawn_blocking(move || verification::decode_qr_image(&image)).await??;
+        verification::scan_qr_code(&client, user_id, request_id, data).await?;
+        Ok(())
+    }
+
+    pub async fn cancel_verification(&self, request_id: &str, other_user_id: &str) -> anyhow::Result<()> {
+        let client = self.client.lock().await.clone().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        let user_id = <&UserId>::try_from(other_user_id)?;
+        let encryption = client.encryption();
+        
+        // The watcher of the request removes it once the cancellation went through
+        match encryption.get_verification(user_id, request_id).await {
+            Some(Verification::SasV1(sas)) => {
+                info!("Cancelling SAS verification: {}", request_id);
+                sas.cancel().await?;
+                return Ok(());
+            }
+            Some(Verification::QrV1(qr)) => {
+                info!("Cancelling QR code verification: {}", request_id);
+                qr.cancel().await?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        if let Some(request) = encryption.get_verification_request(user_id, request_id).await {
             info!("Cancelling verification request: {}", request_id);
             request.cancel().await?;
-            
-            // Remove from our tracking
-            self.verification_requests.write().await.retain(|r| r.request_id != request_id);
-            
-            // Clear active SAS if it matches
-            let mut active_sas = self.active_sas.write().await;
-            if let Some(ref sas) = *active_sas {
-                if sas.request_id == request_id {
-                    *active_sas = None;
+            return Ok(());
+        }
+        
+        // If we can't find it, it might have already been cancelled or completed
+        info!("Verification request not found, assuming already cancelled or completed");
+        self.update_verification(request_id, |info| info.status = "cancelled".to_string()).await;
+        Ok(())
+    }
+
+    /// Add a verification request to the queue and follow its state changes
+    /// until it is done or cancelled
+    async fn track_verification(&self, request_info: VerificationRequestInfo, request: VerificationRequest) {
+        {
+            let mut requests = self.verification_requests.write().await;
+            if requests.iter().any(|r| r.request_id == request_info.request_id) {
+                info!("Verification request {} already in queue, skipping duplicate", request_info.request_id);
+                return;
+            }
+            requests.push(request_info.clone());
+        }
+        info!("Added verification request {} to queue", request_info.request_id);
+        let _ = self.verification_tx.send(request_info);
+        
+        let bot = self.clone();
+        let mut watchers = self.verification_watchers.lock().await;
+        watchers.retain(|watcher| !watcher.is_finished());
+        watchers.push(tokio::spawn(async move { bot.watch_verification_request(request).await }));
+    }
+
+    /// Follow a verification request until it turns into emoji or QR code
+    /// verification, starting emoji verification once the other side accepted
+    /// a request the bot sent
+    async fn watch_verification_request(&self, request: VerificationRequest) {
+        let request_id = request.flow_id().to_string();
+        // Subscribe before reading the current state, so no change is missed
+        let mut changes = std::pin::pin!(request.changes());
+        let mut state = Some(request.state());
+        
+        while let Some(current) = state {
+            match current {
+                VerificationRequestState::Created { .. } | VerificationRequestState::Requested { .. } => {}
+                VerificationRequestState::Ready { their_methods, .. } => {
+                    self.update_verification(&request_id, |info| {
+                        info.status = "ready".to_string();
+                        info.qr_show = their_methods.contains(&VerificationMethod::QrCodeScanV1);
+                        info.qr_scan = their_methods.contains(&VerificationMethod::QrCodeShowV1);
+                    })
+                    .await;
+                    if request.we_started() {
+                        info!("{} accepted the verification request, starting emoji verification", request.other_user_id());
+                        match request.start_sas().await {
+                            Ok(Some(sas)) => return self.watch_sas(&request_id, sas).await,
+                            Ok(None) => {}
+                            Err(e) => warn!("Failed to start emoji verification for {}: {}", request_id, e),
+                        }
+                    }
+                }
+                VerificationRequestState::Transitioned { verification: Verification::SasV1(sas) } => {
+                    return self.watch_sas(&request_id, sas).await;
+                }
+                VerificationRequestState::Transitioned { verification: Verification::QrV1(qr) } => {
+                    // The other side may still switch to emoji verification, or
+                    // a scanned code replaces the shown one, so keep following the request
+                    state = self.watch_qr(&request_id, qr, changes.as_mut()).await;
+                    continue;
+                }
+                VerificationRequestState::Transitioned { .. } => {
+                    warn!("Verification {} switched to an unsupported method", request_id);
+                    return;
+                }
+                VerificationRequestState::Done => {
+                    self.update_verification(&request_id, |info| info.status = "done".to_string()).await;
+                    return;
+                }
+                VerificationRequestState::Cancelled(cancel_info) => {
+                    info!("Verification request {} was cancelled: {}", request_id, cancel_info.reason());
+                    self.update_verification(&request_id, |info| {
+                        info.status = "cancelled".to_string();
+                        info.cancel_reason = Some(cancel_info.reason().to_string());
+                    })
+                    .await;
+                    return;
                 }
             }
-            
-            return Ok(());
+            state = changes.next().await;
         }
+    }
+
+    /// Follow an emoji verification to its end, accepting it when the other
+    /// side started it and publishing the emoji once keys are exchanged
+    async fn watch_sas(&self, request_id: &str, sas: SasVerification) {
+        let mut changes = std::pin::pin!(sas.changes());
+        let mut state = Some(sas.state());
         
-        // The request might have already transitioned to a verification
-        if let Some(verification) = client.encryption().get_verification(user_id, request_id).await {
-            info!("Verification has transitioned, cancelling the verification instead");
-            if let Verification::SasV1(sas) = verification {
-                sas.cancel().await?;
+        while let Some(current) = state {
+            match current {
+                SasState::Started { .. } => {
+                    self.update_verification(request_id, |info| info.status = "started".to_string()).await;
+                    if !sas.we_started() {
+                        info!("{} started emoji verification, accepting it", sas.other_user_id());
+                        if let Err(e) = sas.accept().await {
+                            warn!("Failed to accept SAS verification: {}", e);
+                        }
+                    }
+                }
+                SasState::Accepted { .. } => {
+                    self.update_verification(request_id, |info| info.status = "accepted".to_string()).await;
+                }
+                SasState::KeysExchanged { .. } => {
+                    let emoji = sas.emoji().map(|emojis| {
+                        emojis.iter()
+                            .map(|e| (e.symbol.to_string(), e.description.to_string()))
+                            .collect()
+                    });
+                    let sas_info = SasInfo {
+                        request_id: request_id.to_string(),
+                        emoji,
+                        decimals: sas.decimals(),
+                    };
+                    info!("SAS verification ready for presentation");
+                    self.update_verification(request_id, |info| {
+                        info.status = "keys_exchanged".to_string();
+                        info.sas = Some(sas_info);
+                    })
+                    .await;
+                }
+                SasState::Confirmed => {
+                    self.update_verification(request_id, |info| info.status = "confirmed".to_string()).await;
+                }
+                SasState::Done { .. } => {
+                    info!("Verification with {} completed successfully!", sas.other_user_id());
+                    self.update_verification(request_id, |info| info.status = "done".to_string()).await;
+                    return;
+                }
+                SasState::Cancelled(cancel_info) => {
+                    info!("SAS verification {} was cancelled: {}", request_id, cancel_info.reason());
+                    self.update_verification(request_id, |info| {
+                        info.status = "cancelled".to_string();
+                        info.cancel_reason = Some(cancel_info.reason().to_string());
+                    })
+                    .await;
+                    return;
+                }
+            }
+            state = changes.next().await;
+        }
+    }
+
+    /// Follow a QR code verification until it ends, or until the request
+    /// changes, whose new state is returned
+    async fn watch_qr(
+        &self,
+        request_id: &str,
+        qr: QrVerification,
+        mut request_changes: Pin<&mut impl Stream<Item = VerificationRequestState>>,
+    ) -> Option<VerificationRequestState> {
+        let mut changes = std::pin::pin!(qr.changes());
+        let mut state = Some(qr.state());
+        
+        while let Some(current) = state {
+            match current {
+                // The bot shows its code, the request stays `ready` until it is scanned
+                QrVerificationState::Started => {}
+                QrVerificationState::Scanned => {
+                    info!("{} scanned the QR code, waiting for confirmation", qr.other_user_id());
+                    self.update_verification(request_id, |info| info.status = "scanned".to_string()).await;
+                }
+                QrVerificationState::Reciprocated => {
+                    self.update_verification(request_id, |info| info.status = "reciprocated".to_string()).await;
+                }
+                QrVerificationState::Confirmed => {
+                    self.update_verification(request_id, |info| info.status = "confirmed".to_string()).await;
+                }
+                QrVerificationState::Done { .. } => {
+                    info!("Verification with {} completed successfully!", qr.other_user_id());
+                    self.update_verification(request_id, |info| info.status = "done".to_string()).await;
+                    return None;
+                }
+                QrVerificationState::Cancelled(cancel_info) => {
+                    info!("QR code verification {} was cancelled: {}", request_id, cancel_info.reason());
+                    self.update_verification(request_id, |info| {
+                        info.status = "cancelled".to_string();
+                        info.cancel_reason = Some(cancel_info.reason().to_string());
+                    })
+                    .await;
+                    return None;
+                }
             }
             
-            // Remove from our tracking
-            self.verification_requests.write().await.retain(|r| r.request_id != request_id);
-            
-            // Clear active SAS if it matches
+            tokio::select! {
+                next = changes.next() => state = next,
+                next = request_changes.next() => return next,
+            }
+        }
+        None
+    }
+
+    /// Apply a state change to a tracked verification and send it to the web
+    /// UI. Verifications that are done or cancelled leave the queue.
+    async fn update_verification(&self, request_id: &str, update: impl FnOnce(&mut VerificationRequestInfo)) {
+        let request_info = {
+            let mut requests = self.verification_requests.write().await;
+            let Some(index) = requests.iter().position(|r| r.request_id == request_id) else {
+                return;
+            };
+            update(&mut requests[index]);
+            if requests[index].status == "done" || requests[index].status == "cancelled" {
+                requests.remove(index)
+            } else {
+                requests[index].clone()
+            }
+        };
+        
+        {
             let mut active_sas = self.active_sas.write().await;
-            if let Some(ref sas) = *active_sas {
-                if sas.request_id == request_id {
+            let finished = request_info.status == "done" || request_info.status == "cancelled";
+            if finished {
+                if active_sas.as_ref().is_some_and(|sas| sas.request_id == request_id) {
                     *active_sas = None;
                 }
+            } else if request_info.sas.is_some() {
+                *active_sas = request_info.sas.clone();
             }
-            
-            return Ok(());
         }
         
-        // If we can't find it, it might have already been cancelled or completed
-        // Remove from our tracking anyway
-        self.verification_requests.write().await.retain(|r| r.request_id != request_id);
-        *self.active_sas.write().await = None;
-        
-        info!("Verification request not found, assuming already cancelled or completed");
-        Ok(())
+        let _ = self.verification_tx.send(request_info);
     }
 
     async fn setup_verification_handlers(&self, client: Client) {
         let bot = self.clone();
         
         // Handle incoming verification requests
-        client.add_event_handler(move |ev: matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent| {
+        client.add_event_handler(move |ev: matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent, client: Client| {
             let bot = bot.clone();
             async move {
                 let request_id = ev.content.transaction_id.to_string();
                 info!("Received verification request from {} (id: {})", ev.sender, request_id);
                 
-                // Check for duplicate request_ids to prevent re-prompting
-                {
-                    let existing = bot.verification_requests.read().await;
-                    if existing.iter().any(|r| r.request_id == request_id) {
-                        info!("Verification request {} already in queue, skipping duplicate", request_id);
-                        return;
-                    }
-                }
+                let Some(request) = client.encryption().get_verification_request(&ev.sender, &request_id).await else {
+                    warn!("Verification request {} is unknown to the crypto store, ignoring it", request_id);
+                    return;
+                };
                 
                 let request_info = VerificationRequestInfo {
                     request_id,
                     other_user_id: ev.sender.to_string(),
                     other_device_id: ev.content.from_device.to_string(),
                     status: "pending".to_string(),
+                    outgoing: false,
+                    sas: None,
+                    qr_show: false,
+                    qr_scan: false,
+                    cancel_reason: None,
                 };
-                
-                bot.verification_requests.write().await.push(request_info);
-                info!("Added verification request to queue");
+                bot.track_verification(request_info, request).await;
             }
         });
         
         let bot = self.clone();
         
-        // Monitor for SAS verification updates
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-                
-                let client = {
-                    let client_guard = bot.client.lock().await;
-                    if let Some(ref client) = *client_guard {
-                        client.clone()
-                    } else {
-                        break;
-                    }
+        // Handle in-room verification requests, sent as `m.key.verification.request` messages
+        client.add_event_handler(move |ev: OriginalSyncRoomMessageEvent, client: Client| {
+            let bot = bot.clone();
+            async move {
+                let MessageType::VerificationRequest(content) = &ev.content.msgtype else {
+                    return;
                 };
+                if client.user_id() != Some(&*content.to) || client.user_id() == Some(&*ev.sender) {
+                    return;
+                }
                 
-                // Check all pending verification requests for SAS data
-                let requests = bot.verification_requests.read().await.clone();
+                // In-room verifications are identified by the event ID of their request
+                let request_id = ev.event_id.to_string();
+                info!("Received in-room verification request from {} (id: {})", ev.sender, request_id);
                 
-                for req_info in requests {
-                    if let Ok(user_id) = <&UserId>::try_from(req_info.other_user_id.as_str()) {
-                        if let Some(Verification::SasV1(sas)) = client.encryption().get_verification(user_id, &req_info.request_id).await {
-                            if sas.can_be_presented() {
-                                // Only update if we don't have active SAS or it's for the same request
-                                let should_update = {
-                                    let active = bot.active_sas.read().await;
-                                    active.is_none() || active.as_ref().map(|a| &a.request_id) == Some(&req_info.request_id)
-                                };
-                                
-                                if should_update {
-                                    // Get emoji or decimals
-                                    let emoji = sas.emoji().map(|emojis| {
-                                        emojis.iter()
-                                            .map(|e| (e.symbol.to_string(), e.description.to_string()))
-                                            .collect()
-                                    });
-                                    
-                                    let decimals = sas.decimals();
-                                    
-                                    let sas_info = SasInfo {
-                                        request_id: req_info.request_id.clone(),
-                                        emoji,
-                                        decimals,
-                                    };
-                                    
-                                    *bot.active_sas.write().await = Some(sas_info);
-                                    info!("SAS verification ready for presentation");
-                                }
-                            }
-                            
-                            if sas.is_done() || sas.is_cancelled() {
-                                info!("SAS verification completed or cancelled, cleaning up");
-                                *bot.active_sas.write().await = None;
-                                bot.verification_requests.write().await.retain(|r| r.request_id != req_info.request_id);
-                            }
-                        } else {
-                            // Verification object not found — check if the request itself is still valid
-                            if let Some(request) = client.encryption().get_verification_request(user_id, &req_info.request_id).await {
-                                if request.is_done() || request.is_cancelled() {
-                                    info!("Verification request {} is done or cancelled, removing from queue", req_info.request_id);
-                                    bot.verification_requests.write().await.retain(|r| r.request_id != req_info.request_id);
-                                    let mut active_sas = bot.active_sas.write().await;
-                                    if let Some(ref sas) = *active_sas {
-                                        if sas.request_id == req_info.request_id {
-                                            *active_sas = None;
-                                        }
-                                    }
-                                }
-                            } else {
-                                // Neither verification nor request found — it may have expired or been cleaned up
-                                info!("Verification request {} no longer exists, removing from queue", req_info.request_id);
-                                bot.verification_requests.write().await.retain(|r| r.request_id != req_info.request_id);
-                                let mut active_sas = bot.active_sas.write().await;
-                                if let Some(ref sas) = *active_sas {
-                                    if sas.request_id == req_info.request_id {
-                                        *active_sas = None;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+                let Some(request) = client.encryption().get_verification_request(&ev.sender, &request_id).await else {
+                    warn!("Verification request {} is unknown to the crypto store, ignoring it", request_id);
+                    return;
+                };
+                
+                let request_info = VerificationRequestInfo {
+                    request_id,
+                    other_user_id: ev.sender.to_string(),
+                    other_device_id: content.from_device.to_string(),
+                    status: "pending".to_string(),
+                    outgoing: false,
+                    sas: None,
+                    qr_show: false,
+                    qr_scan: false,
+                    cancel_reason: None,
+                };
+                bot.track_verification(request_info, request).await;
             }
         });
     }
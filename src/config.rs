@@ -1,14 +1,32 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use std::env;
+use std::path::{Component, Path, PathBuf};
+
+/// Account of configs without an `accounts` list, which also owns the
+/// credentials stored by versions before multiple accounts
+pub const DEFAULT_ACCOUNT_ID: &str = "default";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    /// Homeserver of the single-account setup, and default for `accounts`
+    #[serde(default)]
     pub homeserver: String,
+    /// Bot username of the single-account setup, ignored with `accounts`
+    #[serde(default)]
     pub username: String,
     #[serde(default)]
     pub password: String,
-    pub room_id: String,
+    /// Legacy single-room setting, merged into `rooms` by `room_ids()`
+    #[serde(default)]
+    pub room_id: Option<String>,
+    /// Rooms to join and monitor
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Bot accounts run by this process; without any, the top-level
+    /// `homeserver`, `username` and rooms form a single `default` account
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
     pub web: WebConfig,
     #[serde(default)]
     pub message_history: MessageHistoryConfig,
@@ -16,6 +34,41 @@ pub struct Config {
     pub store: StoreConfig,
     #[serde(default)]
     pub database: DatabaseConfig,
+    #[serde(default)]
+    pub media: MediaConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AccountConfig {
+    /// Name of the account in the web UI and API paths
+    pub id: String,
+    /// Defaults to the top-level `homeserver`
+    #[serde(default)]
+    pub homeserver: Option<String>,
+    pub username: String,
+    /// Rooms to join and monitor, the first being the account's default room
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Directory of the account's Matrix store. By default the first account
+    /// takes over `store.path` from the single-account setup, and the others
+    /// use `<store.path>-<id>`
+    #[serde(default)]
+    pub store_path: Option<String>,
+}
+
+/// An account with the defaults of the top-level config filled in
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub homeserver: String,
+    pub username: String,
+    pub room_ids: Vec<String>,
+    pub store_path: String,
+    /// Key of the account's stored credentials, which is `default` for the
+    /// account that took over the single-account setup
+    pub credentials_id: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -37,6 +90,9 @@ pub struct AuthConfig {
 pub struct MessageHistoryConfig {
     #[serde(default = "default_history_limit")]
     pub limit: usize,
+    /// Most messages kept per room in the timeline cache
+    #[serde(default = "default_history_cache_size")]
+    pub cache_size: usize,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -53,10 +109,25 @@ pub struct DatabaseConfig {
     pub path: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MediaConfig {
+    /// Largest attachment (in bytes) the media proxy will serve or accept for upload
+    #[serde(default = "default_media_max_size")]
+    pub max_size: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArchiveConfig {
+    /// Keep a searchable copy of every decrypted message in the database
+    #[serde(default = "default_archive_enabled")]
+    pub enabled: bool,
+}
+
 impl Default for MessageHistoryConfig {
     fn default() -> Self {
         Self {
             limit: default_history_limit(),
+            cache_size: default_history_cache_size(),
         }
     }
 }
@@ -65,6 +136,10 @@ fn default_history_limit() -> usize {
     50
 }
 
+fn default_history_cache_size() -> usize {
+    1000
+}
+
 fn default_store_path() -> String {
     "./matrix_store".to_string()
 }
@@ -73,6 +148,14 @@ fn default_database_path() -> String {
     "./matrix.db".to_string()
 }
 
+fn default_media_max_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_archive_enabled() -> bool {
+    true
+}
+
 fn default_store_passphrase() -> String {
     String::new()
 }
@@ -94,6 +177,22 @@ impl Default for DatabaseConfig {
     }
 }
 
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_media_max_size(),
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_archive_enabled(),
+        }
+    }
+}
+
 impl Config {
     pub fn load(path: &str) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)?;
@@ -140,6 +239,105 @@ impl Config {
         ))
     }
     
+    /// All configured room IDs, with the legacy `room_id` first and duplicates removed.
+    pub fn room_ids(&self) -> Vec<String> {
+        unique_room_ids(self.room_id.iter().chain(self.rooms.iter()))
+    }
+    
+    /// The bot accounts to run, in config order. The first one also serves
+    /// the unprefixed API.
+    pub fn accounts(&self) -> anyhow::Result<Vec<Account>> {
+        if self.accounts.is_empty() {
+            if self.username.is_empty() {
+                anyhow::bail!("Set `username`, or list bot accounts under `accounts`");
+            }
+            return Ok(vec![Account {
+                id: DEFAULT_ACCOUNT_ID.to_string(),
+                homeserver: self.homeserver.clone(),
+                username: self.username.clone(),
+                room_ids: self.room_ids(),
+                store_path: self.store.path.clone(),
+                credentials_id: DEFAULT_ACCOUNT_ID.to_string(),
+            }]);
+        }
+
+        for setting in self.single_account_settings() {
+            tracing::warn!("Ignoring the top-level {}: with `accounts`, set it on each account instead", setting);
+        }
+
+        let mut accounts: Vec<Account> = Vec::new();
+        for account in &self.accounts {
+            if account.id.is_empty()
+                || !account.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!(
+                    "Invalid account id {:?}: use letters, digits, '-' and '_'",
+                    account.id
+                );
+            }
+            if accounts.iter().any(|a| a.id == account.id) {
+                anyhow::bail!("Account id {} is used twice", account.id);
+            }
+
+            // The first account carries on the single-account setup, keeping its
+            // login and encryption keys; the others get a sibling of its store
+            let inherits_default = accounts.is_empty() && account.store_path.is_none();
+            let store_path = match &account.store_path {
+                Some(store_path) => store_path.clone(),
+                None if inherits_default => self.store.path.clone(),
+                None => format!("{}-{}", self.store.path.trim_end_matches('/'), account.id),
+            };
+            let credentials_id = if inherits_default { DEFAULT_ACCOUNT_ID } else { &account.id };
+            if let Some(other) = accounts.iter().find(|a| a.credentials_id == credentials_id) {
+                anyhow::bail!(
+                    "Account {} can't be called `{}`: account {} took over the credentials of the single-account setup under that name",
+                    account.id,
+                    DEFAULT_ACCOUNT_ID,
+                    other.id
+                );
+            }
+            // Logging out deletes an account's store, which must not take another one with it
+            let normalized = normalize_path(Path::new(&store_path))?;
+            for other in &accounts {
+                let other_normalized = normalize_path(Path::new(&other.store_path))?;
+                if normalized.starts_with(&other_normalized) || other_normalized.starts_with(&normalized) {
+                    anyhow::bail!(
+                        "The Matrix stores of accounts {} ({}) and {} ({}) overlap: they must not be equal or inside each other",
+                        other.id,
+                        other.store_path,
+                        account.id,
+                        store_path
+                    );
+                }
+            }
+
+            accounts.push(Account {
+                id: account.id.clone(),
+                homeserver: account.homeserver.clone().unwrap_or_else(|| self.homeserver.clone()),
+                username: account.username.clone(),
+                room_ids: unique_room_ids(account.rooms.iter()),
+                store_path,
+                credentials_id: credentials_id.to_string(),
+            });
+        }
+        Ok(accounts)
+    }
+    
+    /// Single-account settings that are set, from the file or the environment
+    fn single_account_settings(&self) -> Vec<&'static str> {
+        let mut settings = Vec::new();
+        if !self.username.is_empty() {
+            settings.push("`username` (or MATRIX_USERNAME)");
+        }
+        if self.room_id.is_some() {
+            settings.push("`room_id` (or MATRIX_ROOM_ID)");
+        }
+        if !self.rooms.is_empty() {
+            settings.push("`rooms` (or MATRIX_ROOMS)");
+        }
+        settings
+    }
+
     fn apply_env_overrides(&mut self) {
         // Matrix configuration
         if let Ok(val) = env::var("MATRIX_HOMESERVER") {
@@ -149,7 +347,14 @@ impl Config {
             self.username = val;
         }
         if let Ok(val) = env::var("MATRIX_ROOM_ID") {
-            self.room_id = val;
+            self.room_id = Some(val);
+        }
+        if let Ok(val) = env::var("MATRIX_ROOMS") {
+            self.rooms = val
+                .split(',')
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty())
+                .collect();
         }
         
         // Web configuration
@@ -180,6 +385,11 @@ impl Config {
                 self.message_history.limit = limit;
             }
         }
+        if let Ok(val) = env::var("MESSAGE_HISTORY_CACHE_SIZE") {
+            if let Ok(cache_size) = val.parse::<usize>() {
+                self.message_history.cache_size = cache_size;
+            }
+        }
         
         // Store configuration
         if let Ok(val) = env::var("MATRIX_STORE_PATH") {
@@ -193,9 +403,60 @@ impl Config {
         if let Ok(val) = env::var("DATABASE_PATH") {
             self.database.path = val;
         }
+        
+        // Media configuration
+        if let Ok(val) = env::var("MEDIA_MAX_SIZE") {
+            if let Ok(max_size) = val.parse::<u64>() {
+                self.media.max_size = max_size;
+            }
+        }
+        
+        // Archive configuration
+        if let Ok(val) = env::var("ARCHIVE_ENABLED") {
+            if let Ok(enabled) = val.parse::<bool>() {
+                self.archive.enabled = enabled;
+            }
+        }
     }
 }
 
+/// The absolute form of a path, with symbolic links in its existing part
+/// resolved, so paths can be compared whether or not they exist yet
+fn normalize_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut lexical = PathBuf::new();
+    for component in env::current_dir()?.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                lexical.pop();
+            }
+            Component::CurDir => {}
+            other => lexical.push(other),
+        }
+    }
+
+    // Canonicalize the longest existing ancestor and append the rest
+    let mut existing = lexical.as_path();
+    let mut rest = Vec::new();
+    while let (Some(name), Some(parent)) = (existing.file_name(), existing.parent()) {
+        if let Ok(canonical) = existing.canonicalize() {
+            return Ok(rest.iter().rev().fold(canonical, |path, name| path.join(name)));
+        }
+        rest.push(name);
+        existing = parent;
+    }
+    Ok(lexical)
+}
+
+fn unique_room_ids<'a>(rooms: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut room_ids: Vec<String> = Vec::new();
+    for room_id in rooms {
+        if !room_id.is_empty() && !room_ids.contains(room_id) {
+            room_ids.push(room_id.clone());
+        }
+    }
+    room_ids
+}
+
 /// Hash a value using SHA-256 and return as hexadecimal string
 pub fn hash_value(value: &str) -> String {
     let mut hasher = Sha256::new();
@@ -203,3 +464,64 @@ pub fn hash_value(value: &str) -> String {
     let result = hasher.finalize();
     hex::encode(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_accounts(yaml: &str) -> anyhow::Result<Vec<Account>> {
+        let config: Config = serde_yaml::from_str(&format!("web: {{host: localhost, port: 8080}}\nstore: {{path: ./store}}\n{}", yaml))?;
+        config.accounts()
+    }
+
+    #[test]
+    fn single_account_setup_is_the_default_account() {
+        let accounts = parse_accounts("username: bot\nroom_id: '!a:x'\nrooms: ['!b:x', '!a:x']").unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].id, DEFAULT_ACCOUNT_ID);
+        assert_eq!(accounts[0].credentials_id, DEFAULT_ACCOUNT_ID);
+        assert_eq!(accounts[0].store_path, "./store");
+        assert_eq!(accounts[0].room_ids, ["!a:x", "!b:x"]);
+
+        assert!(parse_accounts("rooms: ['!a:x']").is_err());
+    }
+
+    #[test]
+    fn first_account_takes_over_the_single_account_setup() {
+        let accounts = parse_accounts("accounts:\n  - {id: ops, username: opsbot}\n  - {id: alerts, username: alertbot}").unwrap();
+        assert_eq!(accounts[0].store_path, "./store");
+        assert_eq!(accounts[0].credentials_id, DEFAULT_ACCOUNT_ID);
+        assert_eq!(accounts[1].store_path, "./store-alerts");
+        assert_eq!(accounts[1].credentials_id, "alerts");
+    }
+
+    #[test]
+    fn first_account_with_its_own_store_starts_fresh() {
+        let accounts = parse_accounts("accounts:\n  - {id: ops, username: opsbot, store_path: ./ops}\n  - {id: default, username: old}").unwrap();
+        assert_eq!(accounts[0].credentials_id, "ops");
+        assert_eq!(accounts[1].credentials_id, DEFAULT_ACCOUNT_ID);
+        assert_eq!(accounts[1].store_path, "./store-default");
+    }
+
+    #[test]
+    fn rejects_a_second_claim_on_the_default_credentials() {
+        let result = parse_accounts("accounts:\n  - {id: ops, username: opsbot}\n  - {id: default, username: other}");
+        assert!(result.unwrap_err().to_string().contains("single-account setup"));
+    }
+
+    #[test]
+    fn rejects_bad_and_duplicate_ids_and_overlapping_stores() {
+        assert!(parse_accounts("accounts:\n  - {id: 'a b', username: bot}").is_err());
+        assert!(parse_accounts("accounts:\n  - {id: a, username: bot}\n  - {id: a, username: bot2}").is_err());
+        assert!(parse_accounts("accounts:\n  - {id: a, username: bot}\n  - {id: b, username: bot2, store_path: ./store/b}").is_err());
+    }
+
+    #[test]
+    fn lists_ignored_single_account_settings() {
+        let config: Config = serde_yaml::from_str(
+            "web: {host: localhost, port: 8080}\nusername: bot\nrooms: ['!a:x']\naccounts:\n  - {id: ops, username: opsbot}",
+        )
+        .unwrap();
+        assert_eq!(config.single_account_settings().len(), 2);
+    }
+}
@@ -1,15 +1,51 @@
 use anyhow::{Context, Result};
-use rusqlite::Connection;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::db;
+
+/// Raw session columns: (device_id, access_token_encrypted, user_id)
+type SessionRow = (Option<String>, Option<Vec<u8>>, Option<String>);
+type SecretsRow = (i64, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Secrets XORed with SHA-256 of the database password. Only read, to
+/// migrate rows written by earlier versions.
+const SECRET_FORMAT_XOR: i64 = 0;
+/// Secrets sealed with XChaCha20-Poly1305 under an Argon2id key:
+/// `salt || nonce || ciphertext`
+const SECRET_FORMAT_SEALED: i64 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+// Argon2id cost of SECRET_FORMAT_SEALED. Changing these needs a new format.
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+/// The encrypted secrets of the credentials row, exactly as stored
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredSecrets {
+    password_encrypted: Option<Vec<u8>>,
+    access_token_encrypted: Option<Vec<u8>>,
+    secret_format: i64,
+}
 
+/// Credentials of one account, in a database shared by all accounts
 #[derive(Clone)]
 pub struct CredentialStore {
     db_path: String,
+    account: String,
 }
 
 impl CredentialStore {
-    pub fn new(db_path: &str) -> Self {
+    pub fn new(db_path: &str, account: &str) -> Self {
         // Ensure parent directory exists
         if let Some(parent) = Path::new(db_path).parent() {
             if !parent.exists() {
@@ -21,121 +57,227 @@ impl CredentialStore {
         
         Self {
             db_path: db_path.to_string(),
+            account: account.to_string(),
         }
     }
 
-    /// Initialize the credentials database
-    fn init_db(&self, conn: &Connection) -> Result<()> {
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// Open the shared database, for records kept next to the credentials
+    pub fn open_db(&self) -> Result<Connection> {
+        db::open(&self.db_path)
+    }
+
+    /// Run `f` on a blocking thread: key derivation is deliberately slow, so
+    /// sealing or opening a secret must not stall the async workers
+    async fn run_blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> Result<T> + Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .unwrap_or_else(|e| Err(anyhow::anyhow!("Credentials task failed: {}", e)))
+    }
+
+    /// Re-seal secrets still stored in the legacy XOR format.
+    ///
+    /// Must only be called once `sqlite_password` is known to be right (after
+    /// a successful login): XOR decryption can't tell a wrong password apart.
+    fn migrate_legacy_row(&self, conn: &Connection, sqlite_password: &str) -> Result<bool> {
+        let row: Option<SecretsRow> = conn
+            .query_row(
+                "SELECT secret_format, password_encrypted, access_token_encrypted FROM credentials WHERE account = ?1",
+                [&self.account],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let Some((SECRET_FORMAT_XOR, password_encrypted, token_encrypted)) = row else {
+            return Ok(false);
+        };
+
+        let sealed_password = match password_encrypted {
+            Some(password) => Some(seal_secret(&open_xor_secret(&password, sqlite_password)?, sqlite_password)?),
+            None => None,
+        };
+        let sealed_token = match token_encrypted {
+            Some(token) => Some(seal_secret(&open_xor_secret(&token, sqlite_password)?, sqlite_password)?),
+            None => None,
+        };
+
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS credentials (
-                id INTEGER PRIMARY KEY,
-                username TEXT NOT NULL,
-                password_encrypted BLOB NOT NULL,
-                device_id TEXT,
-                access_token_encrypted BLOB,
-                user_id TEXT
-            )",
-            [],
+            "UPDATE credentials SET password_encrypted = ?1, access_token_encrypted = ?2, secret_format = ?3
+             WHERE account = ?4 AND secret_format = ?5",
+            (sealed_password, sealed_token, SECRET_FORMAT_SEALED, &self.account, SECRET_FORMAT_XOR),
         )
-        .context("Failed to create credentials table")?;
-        Ok(())
+        .context("Failed to re-encrypt credentials")?;
+
+        Ok(true)
     }
 
-    /// Simple XOR encryption with key derived from sqlite password
-    /// Note: This provides basic encryption suitable for local storage.
-    /// The security relies on keeping the SQLite password secure.
-    /// For higher security needs, consider using AES with a KDF like Argon2.
-    fn encrypt_password(&self, password: &str, sqlite_password: &str) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(sqlite_password.as_bytes());
-        let key = hasher.finalize();
+    /// Move credentials stored by earlier versions to the sealed format.
+    /// Call after a successful login with the password that unlocked them.
+    pub async fn migrate_legacy_secrets(&self, sqlite_password: &str) -> Result<()> {
+        let sqlite_password = Zeroizing::new(sqlite_password.to_string());
+        self.run_blocking(move |store| {
+            let conn = db::open(&store.db_path)?;
 
-        password
-            .as_bytes()
-            .iter()
-            .enumerate()
-            .map(|(i, &b)| b ^ key[i % key.len()])
-            .collect()
+            if store.migrate_legacy_row(&conn, &sqlite_password)? {
+                tracing::info!("Re-encrypted stored credentials with Argon2id and XChaCha20-Poly1305");
+            }
+
+            Ok(())
+        })
+        .await
     }
 
-    /// Simple XOR decryption with key derived from sqlite password
-    fn decrypt_password(&self, encrypted: &[u8], sqlite_password: &str) -> Result<String> {
-        let mut hasher = Sha256::new();
-        hasher.update(sqlite_password.as_bytes());
-        let key = hasher.finalize();
+    /// Load the encrypted secrets, if credentials are stored
+    pub fn load_secrets(&self) -> Result<Option<StoredSecrets>> {
+        let conn = db::open(&self.db_path)?;
+
+        Ok(conn
+            .query_row(
+                "SELECT password_encrypted, access_token_encrypted, secret_format FROM credentials WHERE account = ?1",
+                [&self.account],
+                |row| {
+                    Ok(StoredSecrets {
+                        password_encrypted: row.get(0)?,
+                        access_token_encrypted: row.get(1)?,
+                        secret_format: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
 
-        let decrypted: Vec<u8> = encrypted
-            .iter()
-            .enumerate()
-            .map(|(i, &b)| b ^ key[i % key.len()])
-            .collect();
+    /// Decrypt secrets with `old_password` and seal them again under `new_password`
+    pub fn reseal_secrets(&self, secrets: &StoredSecrets, old_password: &str, new_password: &str) -> Result<StoredSecrets> {
+        // XOR decryption can't detect a wrong password, which would silently
+        // turn the secrets into garbage
+        if secrets.secret_format != SECRET_FORMAT_SEALED {
+            anyhow::bail!("Stored credentials use an old format, log in once to upgrade them first");
+        }
+
+        let password = secrets
+            .password_encrypted
+            .as_deref()
+            .map(|password| open_sealed_secret(password, old_password))
+            .transpose()?;
+        let access_token = secrets
+            .access_token_encrypted
+            .as_deref()
+            .map(|token| open_sealed_secret(token, old_password))
+            .transpose()?;
+
+        Ok(StoredSecrets {
+            password_encrypted: password.map(|password| seal_secret(&password, new_password)).transpose()?,
+            access_token_encrypted: access_token.map(|token| seal_secret(&token, new_password)).transpose()?,
+            secret_format: SECRET_FORMAT_SEALED,
+        })
+    }
+
+    /// Replace the stored secrets, provided they still are `expected`
+    pub fn replace_secrets(&self, expected: &StoredSecrets, secrets: &StoredSecrets) -> Result<()> {
+        let conn = db::open(&self.db_path)?;
+
+        let rows_affected = conn.execute(
+            "UPDATE credentials SET password_encrypted = ?1, access_token_encrypted = ?2, secret_format = ?3
+             WHERE account = ?4 AND password_encrypted IS ?5 AND access_token_encrypted IS ?6 AND secret_format = ?7",
+            (
+                &secrets.password_encrypted,
+                &secrets.access_token_encrypted,
+                secrets.secret_format,
+                &self.account,
+                &expected.password_encrypted,
+                &expected.access_token_encrypted,
+                expected.secret_format,
+            ),
+        )
+        .context("Failed to update credentials")?;
+
+        if rows_affected == 0 {
+            anyhow::bail!("Stored credentials changed while they were being re-encrypted");
+        }
 
-        String::from_utf8(decrypted).context("Failed to decrypt password")
+        Ok(())
     }
 
     /// Check if credentials exist in the database
     pub fn credentials_exist(&self) -> Result<bool> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
+        let conn = db::open(&self.db_path)?;
 
-        let mut stmt = conn.prepare("SELECT COUNT(*) FROM credentials")?;
-        let count: i64 = stmt.query_row([], |row| row.get(0))?;
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM credentials WHERE account = ?1")?;
+        let count: i64 = stmt.query_row([&self.account], |row| row.get(0))?;
 
         Ok(count > 0)
     }
 
-    /// Store credentials in the database
-    /// Note: This table stores only one set of credentials (id=1) for the bot
-    pub fn store_credentials(
+    /// Store credentials in the database. Without a password the bot can
+    /// only reconnect with the session of an SSO or access-token login.
+    /// Note: This table stores one set of credentials per account
+    pub async fn store_credentials(
         &self,
         username: &str,
-        password: &str,
+        password: Option<&str>,
         sqlite_password: &str,
     ) -> Result<()> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
-
-        let encrypted = self.encrypt_password(password, sqlite_password);
-
-        // Delete existing credentials with id=1 (single credential storage)
-        conn.execute("DELETE FROM credentials WHERE id = 1", [])?;
-
-        // Insert new credentials with id=1
-        conn.execute(
-            "INSERT INTO credentials (id, username, password_encrypted) VALUES (1, ?1, ?2)",
-            (username, encrypted),
-        )
-        .context("Failed to store credentials")?;
-
-        Ok(())
+        let username = username.to_string();
+        let password = password.map(|password| Zeroizing::new(password.to_string()));
+        let sqlite_password = Zeroizing::new(sqlite_password.to_string());
+        self.run_blocking(move |store| {
+            let conn = db::open(&store.db_path)?;
+
+            let encrypted = password
+                .map(|password| seal_secret(&password, &sqlite_password))
+                .transpose()?;
+
+            // Replace the account's existing credentials
+            conn.execute("DELETE FROM credentials WHERE account = ?1", [&store.account])?;
+
+            conn.execute(
+                "INSERT INTO credentials (account, username, password_encrypted, secret_format) VALUES (?1, ?2, ?3, ?4)",
+                (&store.account, username, encrypted, SECRET_FORMAT_SEALED),
+            )
+            .context("Failed to store credentials")?;
+
+            Ok(())
+        })
+        .await
     }
 
     /// Retrieve credentials from the database
-    /// Note: This table stores only one set of credentials (id=1) for the bot
-    pub fn get_credentials(&self, sqlite_password: &str) -> Result<(String, String)> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
-
-        let mut stmt = conn.prepare("SELECT username, password_encrypted FROM credentials WHERE id = 1")?;
-        let (username, encrypted): (String, Vec<u8>) = stmt.query_row([], |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        })?;
-
-        let password = self.decrypt_password(&encrypted, sqlite_password)?;
-
-        Ok((username, password))
+    pub async fn get_credentials(&self, sqlite_password: &str) -> Result<(String, Option<String>)> {
+        let sqlite_password = Zeroizing::new(sqlite_password.to_string());
+        self.run_blocking(move |store| {
+            let conn = db::open(&store.db_path)?;
+
+            let mut stmt = conn.prepare("SELECT username, password_encrypted, secret_format FROM credentials WHERE account = ?1")?;
+            let (username, encrypted, format): (String, Option<Vec<u8>>, i64) = stmt.query_row([&store.account], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+
+            let password = encrypted
+                .map(|encrypted| open_secret(&encrypted, format, &sqlite_password))
+                .transpose()?;
+
+            Ok((username, password))
+        })
+        .await
     }
 
     /// Check if a session exists (has device_id, access_token, and user_id)
     pub fn session_exists(&self) -> Result<bool> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
+        let conn = db::open(&self.db_path)?;
 
         let mut stmt = conn.prepare(
-            "SELECT device_id, access_token_encrypted, user_id FROM credentials WHERE id = 1"
+            "SELECT device_id, access_token_encrypted, user_id FROM credentials WHERE account = ?1"
         )?;
         
-        let result: rusqlite::Result<(Option<String>, Option<Vec<u8>>, Option<String>)> = stmt.query_row([], |row| {
+        let result: rusqlite::Result<SessionRow> = stmt.query_row([&self.account], |row| {
             Ok((row.get(0)?, row.get(1)?, row.get(2)?))
         });
 
@@ -150,71 +292,283 @@ impl CredentialStore {
     }
 
     /// Store session data (device_id, access_token, and user_id)
-    pub fn store_session(
+    pub async fn store_session(
         &self,
         device_id: &str,
         access_token: &str,
         user_id: &str,
         sqlite_password: &str,
     ) -> Result<()> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
+        let device_id = device_id.to_string();
+        let access_token = Zeroizing::new(access_token.to_string());
+        let user_id = user_id.to_string();
+        let sqlite_password = Zeroizing::new(sqlite_password.to_string());
+        self.run_blocking(move |store| {
+            let conn = db::open(&store.db_path)?;
+
+            // A new session means the login just succeeded, so the password is
+            // right and legacy secrets can be re-sealed before adding the token
+            store.migrate_legacy_row(&conn, &sqlite_password)?;
+
+            let encrypted_token = seal_secret(&access_token, &sqlite_password)?;
+
+            // Update the session fields for the existing credentials row
+            let rows_affected = conn.execute(
+                "UPDATE credentials SET device_id = ?1, access_token_encrypted = ?2, user_id = ?3 WHERE account = ?4",
+                (device_id, encrypted_token, user_id, &store.account),
+            )
+            .context("Failed to store session")?;
+
+            if rows_affected == 0 {
+                anyhow::bail!("No credentials row found to update session. Please login first.");
+            }
 
-        let encrypted_token = self.encrypt_password(access_token, sqlite_password);
+            Ok(())
+        })
+        .await
+    }
 
-        // Update the session fields for the existing credentials row
+    /// Retrieve session data (device_id, access_token, and user_id)
+    pub async fn get_session(&self, sqlite_password: &str) -> Result<(String, String, String)> {
+        let sqlite_password = Zeroizing::new(sqlite_password.to_string());
+        self.run_blocking(move |store| {
+            let conn = db::open(&store.db_path)?;
+
+            let mut stmt = conn.prepare(
+                "SELECT device_id, access_token_encrypted, user_id, secret_format FROM credentials WHERE account = ?1"
+            )?;
+            
+            let ((device_id, encrypted_token, user_id), format): (SessionRow, i64) = stmt.query_row([&store.account], |row| {
+                Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
+            })?;
+
+            let device_id = device_id.ok_or_else(|| anyhow::anyhow!("Session device_id is NULL"))?;
+            let encrypted_token = encrypted_token.ok_or_else(|| anyhow::anyhow!("Session access_token is NULL"))?;
+            let user_id = user_id.ok_or_else(|| anyhow::anyhow!("Session user_id is NULL"))?;
+
+            let access_token = open_secret(&encrypted_token, format, &sqlite_password)?;
+
+            Ok((device_id, access_token, user_id))
+        })
+        .await
+    }
+
+    /// Clear session data (device_id, access_token, and user_id)
+    /// This should be called when logging out to prevent attempting to restore an invalid session
+    pub fn clear_session(&self) -> Result<()> {
+        let conn = db::open(&self.db_path)?;
+
+        // Clear the session fields by setting them to NULL
         let rows_affected = conn.execute(
-            "UPDATE credentials SET device_id = ?1, access_token_encrypted = ?2, user_id = ?3 WHERE id = 1",
-            (device_id, encrypted_token, user_id),
+            "UPDATE credentials SET device_id = NULL, access_token_encrypted = NULL, user_id = NULL WHERE account = ?1",
+            [&self.account],
         )
-        .context("Failed to store session")?;
+        .context("Failed to clear session")?;
 
         if rows_affected == 0 {
-            anyhow::bail!("No credentials row found to update session. Please login first.");
+            tracing::warn!("No credentials row found when clearing session - this may indicate the bot was never logged in or the database is in an inconsistent state");
         }
 
         Ok(())
     }
 
-    /// Retrieve session data (device_id, access_token, and user_id)
-    pub fn get_session(&self, sqlite_password: &str) -> Result<(String, String, String)> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
+    /// Delete the stored credentials, so the next login asks for the Matrix password again
+    pub fn clear_credentials(&self) -> Result<()> {
+        let conn = db::open(&self.db_path)?;
 
-        let mut stmt = conn.prepare(
-            "SELECT device_id, access_token_encrypted, user_id FROM credentials WHERE id = 1"
-        )?;
-        
-        let (device_id, encrypted_token, user_id): (Option<String>, Option<Vec<u8>>, Option<String>) = stmt.query_row([], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-        })?;
+        conn.execute("DELETE FROM credentials WHERE account = ?1", [&self.account])
+            .context("Failed to delete credentials")?;
 
-        let device_id = device_id.ok_or_else(|| anyhow::anyhow!("Session device_id is NULL"))?;
-        let encrypted_token = encrypted_token.ok_or_else(|| anyhow::anyhow!("Session access_token is NULL"))?;
-        let user_id = user_id.ok_or_else(|| anyhow::anyhow!("Session user_id is NULL"))?;
+        Ok(())
+    }
+}
 
-        let access_token = self.decrypt_password(&encrypted_token, sqlite_password)?;
+/// Derive the sealing key for one secret from the database password
+fn derive_key(sqlite_password: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(sqlite_password.as_bytes(), salt, key.as_mut())
+        .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypt and authenticate a secret under a key derived with a fresh salt
+fn seal_secret(secret: &str, sqlite_password: &str) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(sqlite_password, &salt)?;
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, secret.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt secret"))?;
+
+    let mut sealed = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
 
-        Ok((device_id, access_token, user_id))
+/// Decrypt a secret stored in the given format
+fn open_secret(encrypted: &[u8], format: i64, sqlite_password: &str) -> Result<String> {
+    match format {
+        SECRET_FORMAT_XOR => open_xor_secret(encrypted, sqlite_password),
+        SECRET_FORMAT_SEALED => open_sealed_secret(encrypted, sqlite_password),
+        _ => anyhow::bail!("Unsupported secret format {}", format),
     }
+}
 
-    /// Clear session data (device_id, access_token, and user_id)
-    /// This should be called when logging out to prevent attempting to restore an invalid session
-    pub fn clear_session(&self) -> Result<()> {
-        let conn = Connection::open(&self.db_path)?;
-        self.init_db(&conn)?;
+fn open_sealed_secret(sealed: &[u8], sqlite_password: &str) -> Result<String> {
+    if sealed.len() < SALT_LEN + NONCE_LEN {
+        anyhow::bail!("Stored secret is truncated");
+    }
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
 
-        // Clear the session fields by setting them to NULL
-        let rows_affected = conn.execute(
-            "UPDATE credentials SET device_id = NULL, access_token_encrypted = NULL, user_id = NULL WHERE id = 1",
-            [],
-        )
-        .context("Failed to clear session")?;
+    let key = derive_key(sqlite_password, salt)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+    // The tag check fails for a wrong password as well as for tampered data
+    let plaintext = cipher
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Wrong database password or corrupted credentials"))?;
 
-        if rows_affected == 0 {
-            tracing::warn!("No credentials row found when clearing session - this may indicate the bot was never logged in or the database is in an inconsistent state");
+    String::from_utf8(plaintext).context("Stored secret is not valid UTF-8")
+}
+
+/// Decrypt a secret XORed with SHA-256 of the database password
+fn open_xor_secret(encrypted: &[u8], sqlite_password: &str) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(sqlite_password.as_bytes());
+    let key: Zeroizing<[u8; 32]> = Zeroizing::new(hasher.finalize().into());
+
+    let decrypted: Vec<u8> = encrypted
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| b ^ key[i % key.len()])
+        .collect();
+
+    String::from_utf8(decrypted).context("Failed to decrypt password")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A credentials store on its own database file, deleted when dropped
+    struct TempStore {
+        store: CredentialStore,
+        path: PathBuf,
+    }
+
+    impl TempStore {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("matrix-web-credentials-{}-{}.sqlite3", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self {
+                store: CredentialStore::new(path.to_str().unwrap(), "default"),
+                path,
+            }
         }
+    }
 
-        Ok(())
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn xor_secret(secret: &str, sqlite_password: &str) -> Vec<u8> {
+        let key = Sha256::digest(sqlite_password.as_bytes());
+        secret.bytes().enumerate().map(|(i, b)| b ^ key[i % key.len()]).collect()
+    }
+
+    #[test]
+    fn sealed_secret_round_trip() {
+        let sealed = seal_secret("matrix password", "db password").unwrap();
+        assert_eq!(open_sealed_secret(&sealed, "db password").unwrap(), "matrix password");
+
+        // Every secret gets a fresh salt and nonce
+        assert_ne!(sealed, seal_secret("matrix password", "db password").unwrap());
+    }
+
+    #[test]
+    fn sealed_secret_rejects_wrong_password_and_tampering() {
+        let mut sealed = seal_secret("matrix password", "db password").unwrap();
+        assert!(open_sealed_secret(&sealed, "other password").is_err());
+
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open_sealed_secret(&sealed, "db password").is_err());
+        assert!(open_sealed_secret(&sealed[..SALT_LEN], "db password").is_err());
+    }
+
+    #[tokio::test]
+    async fn stored_credentials_round_trip() {
+        let temp = TempStore::new("round-trip");
+        let store = &temp.store;
+
+        store.store_credentials("bot", Some("matrix password"), "db password").await.unwrap();
+        store.store_session("DEVICE", "token", "@bot:example.org", "db password").await.unwrap();
+
+        let (username, password) = store.get_credentials("db password").await.unwrap();
+        assert_eq!(username, "bot");
+        assert_eq!(password.as_deref(), Some("matrix password"));
+        let session = store.get_session("db password").await.unwrap();
+        assert_eq!(session, ("DEVICE".to_string(), "token".to_string(), "@bot:example.org".to_string()));
+
+        assert!(store.get_credentials("other password").await.is_err());
+        assert!(store.get_session("other password").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reseal_needs_the_current_password() {
+        let temp = TempStore::new("reseal");
+        let store = &temp.store;
+        store.store_credentials("bot", Some("matrix password"), "old").await.unwrap();
+        let secrets = store.load_secrets().unwrap().unwrap();
+
+        assert!(store.reseal_secrets(&secrets, "wrong", "new").is_err());
+
+        let resealed = store.reseal_secrets(&secrets, "old", "new").unwrap();
+        store.replace_secrets(&secrets, &resealed).unwrap();
+        let (_, password) = store.get_credentials("new").await.unwrap();
+        assert_eq!(password.as_deref(), Some("matrix password"));
+        assert!(store.get_credentials("old").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_xor_rows_are_resealed() {
+        let temp = TempStore::new("legacy");
+        let store = &temp.store;
+        store
+            .open_db()
+            .unwrap()
+            .execute(
+                "INSERT INTO credentials (account, username, password_encrypted, access_token_encrypted, device_id, user_id, secret_format)
+                 VALUES ('default', 'bot', ?1, ?2, 'DEVICE', '@bot:example.org', ?3)",
+                (xor_secret("matrix password", "db password"), xor_secret("token", "db password"), SECRET_FORMAT_XOR),
+            )
+            .unwrap();
+
+        // Legacy rows are still readable before the migration
+        let (_, password) = store.get_credentials("db password").await.unwrap();
+        assert_eq!(password.as_deref(), Some("matrix password"));
+        assert!(store.reseal_secrets(&store.load_secrets().unwrap().unwrap(), "db password", "new").is_err());
+
+        store.migrate_legacy_secrets("db password").await.unwrap();
+
+        let secrets = store.load_secrets().unwrap().unwrap();
+        assert_eq!(secrets.secret_format, SECRET_FORMAT_SEALED);
+        let (_, password) = store.get_credentials("db password").await.unwrap();
+        assert_eq!(password.as_deref(), Some("matrix password"));
+        let (_, token, _) = store.get_session("db password").await.unwrap();
+        assert_eq!(token, "token");
+        // Sealed secrets, unlike XOR, detect a wrong password
+        assert!(store.get_credentials("other password").await.is_err());
     }
 }
@@ -0,0 +1,380 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+/// One step of the database schema. Migrations run in order, each exactly
+/// once, and are never edited after release: schema changes get a new one.
+struct Migration {
+    version: i64,
+    description: &'static str,
+    apply: fn(&Transaction) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "credentials table",
+        apply: create_credentials,
+    },
+    Migration {
+        version: 2,
+        description: "secret format of stored credentials",
+        apply: add_secret_format,
+    },
+    Migration {
+        version: 3,
+        description: "message archive with full-text index",
+        apply: create_message_archive,
+    },
+    Migration {
+        version: 4,
+        description: "optional password for SSO and access-token logins",
+        apply: make_password_optional,
+    },
+    Migration {
+        version: 5,
+        description: "credentials per account",
+        apply: add_credentials_account,
+    },
+    Migration {
+        version: 6,
+        description: "journal of passphrase changes",
+        apply: create_passphrase_journal,
+    },
+];
+
+/// Open the database and bring its schema up to date
+pub fn open(db_path: &str) -> Result<Connection> {
+    let mut conn = Connection::open(db_path)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create schema_version table")?;
+
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if current_version(conn)? == latest {
+        return Ok(());
+    }
+
+    // Take the write lock before reading the version again, so concurrent
+    // connections don't apply the same migration twice
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let current = current_version(&tx)?;
+    if current > latest {
+        anyhow::bail!(
+            "Database schema version {} is newer than this build supports ({}), refusing to open it",
+            current,
+            latest
+        );
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        tracing::info!("Migrating database to version {}: {}", migration.version, migration.description);
+        (migration.apply)(&tx)
+            .with_context(|| format!("Database migration {} failed", migration.version))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, strftime('%s', 'now'))",
+            (migration.version, migration.description),
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+fn current_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?)
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> Result<bool> {
+    Ok(tx
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists((table, column))?)
+}
+
+// Databases from before schema versioning already have some of these
+// tables, so the early migrations tolerate existing objects.
+
+fn create_credentials(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS credentials (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            password_encrypted BLOB NOT NULL,
+            device_id TEXT,
+            access_token_encrypted BLOB,
+            user_id TEXT
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_secret_format(tx: &Transaction) -> Result<()> {
+    // Existing rows hold XOR-obfuscated secrets (format 0)
+    if !has_column(tx, "credentials", "secret_format")? {
+        tx.execute(
+            "ALTER TABLE credentials ADD COLUMN secret_format INTEGER NOT NULL DEFAULT 0",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn create_message_archive(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS archived_messages (
+            id INTEGER PRIMARY KEY,
+            event_id TEXT NOT NULL UNIQUE,
+            room_id TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            msgtype TEXT NOT NULL,
+            body TEXT NOT NULL,
+            event_json TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS archived_messages_room_timestamp
+            ON archived_messages (room_id, timestamp);
+        CREATE VIRTUAL TABLE IF NOT EXISTS archived_messages_fts
+            USING fts5(body, content='archived_messages', content_rowid='id');
+        CREATE TRIGGER IF NOT EXISTS archived_messages_ai AFTER INSERT ON archived_messages BEGIN
+            INSERT INTO archived_messages_fts (rowid, body) VALUES (new.id, new.body);
+        END;
+        CREATE TRIGGER IF NOT EXISTS archived_messages_ad AFTER DELETE ON archived_messages BEGIN
+            INSERT INTO archived_messages_fts (archived_messages_fts, rowid, body)
+                VALUES ('delete', old.id, old.body);
+        END;
+        CREATE TRIGGER IF NOT EXISTS archived_messages_au AFTER UPDATE ON archived_messages BEGIN
+            INSERT INTO archived_messages_fts (archived_messages_fts, rowid, body)
+                VALUES ('delete', old.id, old.body);
+            INSERT INTO archived_messages_fts (rowid, body) VALUES (new.id, new.body);
+        END;",
+    )?;
+    Ok(())
+}
+
+fn make_password_optional(tx: &Transaction) -> Result<()> {
+    // SQLite can't drop a NOT NULL constraint in place
+    tx.execute_batch(
+        "CREATE TABLE credentials_new (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            password_encrypted BLOB,
+            device_id TEXT,
+            access_token_encrypted BLOB,
+            user_id TEXT,
+            secret_format INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT INTO credentials_new
+            (id, username, password_encrypted, device_id, access_token_encrypted, user_id, secret_format)
+            SELECT id, username, password_encrypted, device_id, access_token_encrypted, user_id, secret_format
+            FROM credentials;
+        DROP TABLE credentials;
+        ALTER TABLE credentials_new RENAME TO credentials;",
+    )?;
+    Ok(())
+}
+
+fn add_credentials_account(tx: &Transaction) -> Result<()> {
+    // The single row of earlier versions belongs to the `default` account
+    tx.execute_batch(
+        "ALTER TABLE credentials ADD COLUMN account TEXT NOT NULL DEFAULT 'default';
+        CREATE UNIQUE INDEX credentials_account ON credentials (account);",
+    )?;
+    Ok(())
+}
+
+fn create_passphrase_journal(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE passphrase_changes (
+            account TEXT NOT NULL,
+            target TEXT NOT NULL,
+            old_value BLOB NOT NULL,
+            new_value BLOB NOT NULL,
+            reverting INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (account, target)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A database file of its own, deleted when dropped
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("matrix-web-db-{}-{}.sqlite3", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn latest() -> i64 {
+        MIGRATIONS.last().unwrap().version
+    }
+
+    fn versions(conn: &Connection) -> Vec<i64> {
+        conn.prepare("SELECT version FROM schema_version ORDER BY version")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn migrations_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn creates_a_new_database() {
+        let db = TempDb::new("new");
+        let conn = open(db.path()).unwrap();
+        assert_eq!(versions(&conn), (1..=latest()).collect::<Vec<_>>());
+
+        conn.execute(
+            "INSERT INTO credentials (account, username, password_encrypted, secret_format) VALUES ('ops', 'bot', NULL, 1)",
+            [],
+        )
+        .unwrap();
+        for table in ["archived_messages", "archived_messages_fts", "passphrase_changes"] {
+            let exists: bool = conn
+                .query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = ?1)", [table], |row| row.get(0))
+                .unwrap();
+            assert!(exists, "{} is missing", table);
+        }
+    }
+
+    #[test]
+    fn upgrades_databases_from_before_versioning() {
+        // The credentials table as created by the first release, and after
+        // the secret format was added, both without a schema_version table
+        let baselines = [
+            "CREATE TABLE credentials (
+                id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL,
+                password_encrypted BLOB NOT NULL,
+                device_id TEXT,
+                access_token_encrypted BLOB,
+                user_id TEXT
+            );
+            INSERT INTO credentials (username, password_encrypted, device_id, access_token_encrypted, user_id)
+                VALUES ('bot', x'0102', 'DEVICE', x'0304', '@bot:example.org');",
+            "CREATE TABLE credentials (
+                id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL,
+                password_encrypted BLOB NOT NULL,
+                device_id TEXT,
+                access_token_encrypted BLOB,
+                user_id TEXT,
+                secret_format INTEGER NOT NULL DEFAULT 0
+            );
+            INSERT INTO credentials (username, password_encrypted, device_id, access_token_encrypted, user_id, secret_format)
+                VALUES ('bot', x'0102', 'DEVICE', x'0304', '@bot:example.org', 0);",
+        ];
+
+        for (i, baseline) in baselines.iter().enumerate() {
+            let db = TempDb::new(&format!("baseline-{}", i));
+            Connection::open(db.path()).unwrap().execute_batch(baseline).unwrap();
+
+            let conn = open(db.path()).unwrap();
+            assert_eq!(versions(&conn), (1..=latest()).collect::<Vec<_>>());
+
+            // The existing row survives the table rebuilds, as the default account
+            let row: (String, String, Vec<u8>, String, Vec<u8>, String, i64) = conn
+                .query_row(
+                    "SELECT account, username, password_encrypted, device_id, access_token_encrypted, user_id, secret_format
+                     FROM credentials",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?)),
+                )
+                .unwrap();
+            assert_eq!(
+                row,
+                (
+                    "default".to_string(),
+                    "bot".to_string(),
+                    vec![1, 2],
+                    "DEVICE".to_string(),
+                    vec![3, 4],
+                    "@bot:example.org".to_string(),
+                    0
+                )
+            );
+
+            // The password is optional now, and accounts are unique
+            conn.execute("INSERT INTO credentials (account, username) VALUES ('ops', 'opsbot')", []).unwrap();
+            assert!(conn.execute("INSERT INTO credentials (account, username) VALUES ('ops', 'other')", []).is_err());
+        }
+    }
+
+    #[test]
+    fn reopening_is_idempotent() {
+        let db = TempDb::new("reopen");
+        let conn = open(db.path()).unwrap();
+        conn.execute("INSERT INTO credentials (account, username) VALUES ('default', 'bot')", []).unwrap();
+        let applied: Vec<(i64, i64)> = conn
+            .prepare("SELECT version, applied_at FROM schema_version ORDER BY version")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        drop(conn);
+
+        let conn = open(db.path()).unwrap();
+        let reopened: Vec<(i64, i64)> = conn
+            .prepare("SELECT version, applied_at FROM schema_version ORDER BY version")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(applied, reopened);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM credentials", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let db = TempDb::new("newer");
+        open(db.path())
+            .unwrap()
+            .execute(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'from the future', 0)",
+                [latest() + 1],
+            )
+            .unwrap();
+
+        let error = open(db.path()).unwrap_err().to_string();
+        assert!(error.contains("newer than this build supports"), "{}", error);
+    }
+}
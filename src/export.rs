@@ -0,0 +1,529 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use matrix_sdk::bytes::Bytes;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+use tokio::sync::mpsc;
+
+use crate::bot::MatrixBot;
+use crate::credentials::CredentialStore;
+use crate::messages::MessageEvent;
+
+/// File format of a room export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON message event per line
+    JsonLines,
+    /// Self-contained HTML transcript
+    Html,
+    /// Plain-text transcript
+    Text,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::JsonLines => "application/x-ndjson",
+            ExportFormat::Html => "text/html; charset=utf-8",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Html => "html",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" | "json" => Ok(ExportFormat::JsonLines),
+            "html" => Ok(ExportFormat::Html),
+            "txt" | "text" => Ok(ExportFormat::Text),
+            _ => bail!("Unknown export format: {} (expected jsonl, html or txt)", s),
+        }
+    }
+}
+
+/// Range of message timestamps to export, in milliseconds since the epoch
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DateRange {
+    /// Earliest timestamp included
+    pub since: Option<u64>,
+    /// First timestamp no longer included
+    pub until: Option<u64>,
+}
+
+impl DateRange {
+    /// Parse optional bounds given as `YYYY-MM-DD` dates (UTC) or RFC 3339
+    /// timestamps. An `until` date includes the whole day.
+    pub fn parse(since: Option<&str>, until: Option<&str>) -> Result<Self> {
+        let range = Self {
+            since: since.map(|s| parse_date_bound(s, false)).transpose()?,
+            until: until.map(|s| parse_date_bound(s, true)).transpose()?,
+        };
+
+        if let (Some(since), Some(until)) = (range.since, range.until) {
+            if since >= until {
+                bail!("Export range is empty: `since` must be before `until`");
+            }
+        }
+
+        Ok(range)
+    }
+
+    pub fn contains(&self, timestamp: u64) -> bool {
+        self.since.is_none_or(|since| timestamp >= since) && self.until.is_none_or(|until| timestamp < until)
+    }
+}
+
+fn parse_date_bound(value: &str, end_of_day: bool) -> Result<u64> {
+    let timestamp = if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let date = if end_of_day {
+            date.succ_opt().context("Date out of range")?
+        } else {
+            date
+        };
+        date.and_hms_opt(0, 0, 0)
+            .context("Date out of range")?
+            .and_utc()
+            .timestamp_millis()
+    } else {
+        DateTime::parse_from_rfc3339(value)
+            .with_context(|| format!("Invalid date: {} (expected YYYY-MM-DD or an RFC 3339 timestamp)", value))?
+            .timestamp_millis()
+    };
+
+    u64::try_from(timestamp).with_context(|| format!("Date before 1970: {}", value))
+}
+
+/// Suggested file name for an export of a room
+pub fn export_file_name(room_id: &str, format: ExportFormat) -> String {
+    let room: String = room_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}-{}.{}", room.trim_matches('_'), Utc::now().format("%Y%m%d"), format.extension())
+}
+
+/// A room's messages in a date range, oldest first, ready to be written out
+pub struct RoomExport {
+    pub room_id: String,
+    pub room_name: Option<String>,
+    pub messages: Vec<MessageEvent>,
+}
+
+impl RoomExport {
+    /// Fetch a room's history in `range` from the homeserver
+    pub async fn fetch(bot: &MatrixBot, room_id: &str, range: DateRange) -> Result<Self> {
+        let mut messages = bot.export_history(room_id, range.since).await?;
+        messages.retain(|m| range.contains(m.timestamp));
+
+        let room_name = bot
+            .get_rooms()
+            .await
+            .into_iter()
+            .find(|room| room.room_id == room_id)
+            .and_then(|room| room.name);
+
+        Ok(Self {
+            room_id: room_id.to_string(),
+            room_name,
+            messages,
+        })
+    }
+
+    /// Write the messages in the given format, one message at a time
+    pub fn write<W: Write>(&self, out: &mut W, format: ExportFormat) -> io::Result<()> {
+        match format {
+            ExportFormat::JsonLines => {
+                for message in &self.messages {
+                    serde_json::to_writer(&mut *out, message)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            ExportFormat::Html => self.write_html(out)?,
+            ExportFormat::Text => self.write_text(out)?,
+        }
+        out.flush()
+    }
+
+    fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let by_id: HashMap<&str, &MessageEvent> = self.messages.iter().map(|m| (m.event_id.as_str(), m)).collect();
+
+        writeln!(out, "Room: {} ({})", self.title(), self.room_id)?;
+        writeln!(out, "Exported: {}", Utc::now().format("%Y-%m-%d %H:%M:%S UTC"))?;
+        writeln!(out, "Messages: {}", self.messages.len())?;
+        writeln!(out)?;
+
+        for message in &self.messages {
+            if let Some((label, target)) = reference(message, &by_id) {
+                writeln!(out, "    ({} {})", label, target)?;
+            }
+
+            let body = match (&message.attachment, message.msgtype.as_str()) {
+                (Some(attachment), _) => format!("[{}: {}]", message.msgtype, attachment.filename),
+                (None, "m.location") => format!("[location: {}]", message.geo_uri.as_deref().unwrap_or(&message.body)),
+                _ => message.body.clone(),
+            };
+            let edited = if message.edited { " (edited)" } else { "" };
+            let timestamp = format_timestamp(message.timestamp);
+            if message.msgtype == "m.emote" {
+                writeln!(out, "[{}] * {} {}{}", timestamp, sender_name(message), body, edited)?;
+            } else {
+                writeln!(out, "[{}] <{}> {}{}", timestamp, sender_name(message), body, edited)?;
+            }
+
+            if let Some(reactions) = reactions_summary(message) {
+                writeln!(out, "    reactions: {}", reactions)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_html<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let by_id: HashMap<&str, &MessageEvent> = self.messages.iter().map(|m| (m.event_id.as_str(), m)).collect();
+        let title = escape_html(self.title());
+
+        write!(
+            out,
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta http-equiv=\"Content-Security-Policy\" content=\"default-src 'none'; style-src 'unsafe-inline'\">\n\
+             <title>{title}</title>\n<style>\n{style}\n</style>\n</head>\n<body>\n\
+             <h1>{title}</h1>\n<p class=\"meta\">{room_id} &middot; exported {exported} &middot; {count} messages</p>\n",
+            title = title,
+            style = HTML_STYLE,
+            room_id = escape_html(&self.room_id),
+            exported = Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+            count = self.messages.len(),
+        )?;
+
+        for message in &self.messages {
+            let class = if message.msgtype == "m.emote" { "message emote" } else { "message" };
+            writeln!(out, "<div class=\"{}\" id=\"{}\">", class, escape_html(&message.event_id))?;
+
+            if let Some((label, target)) = reference(message, &by_id) {
+                writeln!(out, "<div class=\"reference\">{} {}</div>", label, escape_html(&target))?;
+            }
+
+            write!(
+                out,
+                "<span class=\"meta\">{}</span> <span class=\"sender\" title=\"{}\">{}</span> ",
+                format_timestamp(message.timestamp),
+                escape_html(&message.sender),
+                escape_html(sender_name(message)),
+            )?;
+
+            // Formatted bodies were sanitized to the spec's allowed tags when the message was read
+            match (&message.attachment, &message.formatted_body) {
+                (Some(attachment), _) => write!(
+                    out,
+                    "<span class=\"body plain\">[{}: {}]</span>",
+                    escape_html(&message.msgtype),
+                    escape_html(&attachment.filename),
+                )?,
+                (None, Some(html)) => write!(out, "<div class=\"body\">{}</div>", html)?,
+                (None, None) => write!(out, "<span class=\"body plain\">{}</span>", escape_html(&message.body))?,
+            }
+            if message.edited {
+                out.write_all(b" <span class=\"meta\">(edited)</span>")?;
+            }
+            writeln!(out)?;
+
+            if let Some(reactions) = reactions_summary(message) {
+                writeln!(out, "<div class=\"reactions\">{}</div>", escape_html(&reactions))?;
+            }
+            out.write_all(b"</div>\n")?;
+        }
+
+        out.write_all(b"</body>\n</html>\n")
+    }
+
+    fn title(&self) -> &str {
+        self.room_name.as_deref().unwrap_or(&self.room_id)
+    }
+}
+
+/// Sends what is written to it as body chunks, so an export written on a
+/// blocking thread can be streamed as a response
+pub struct ChunkWriter(pub mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Export download was cancelled"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    DateTime::from_timestamp_millis(timestamp as i64)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn sender_name(message: &MessageEvent) -> &str {
+    message.sender_display_name.as_deref().unwrap_or(&message.sender)
+}
+
+/// One-line description of a message for reply and thread references
+fn preview(message: &MessageEvent) -> String {
+    let body: String = message.body.lines().next().unwrap_or_default().chars().take(80).collect();
+    format!("{}: {}", sender_name(message), body)
+}
+
+/// Where a message points to: the message it replies to, or its thread root
+fn reference(message: &MessageEvent, by_id: &HashMap<&str, &MessageEvent>) -> Option<(&'static str, String)> {
+    let (label, event_id) = match (&message.in_reply_to, &message.thread_root) {
+        (Some(event_id), _) => ("in reply to", event_id),
+        (None, Some(event_id)) => ("in thread", event_id),
+        (None, None) => return None,
+    };
+    let target = by_id
+        .get(event_id.as_str())
+        .map(|m| preview(m))
+        .unwrap_or_else(|| event_id.clone());
+    Some((label, target))
+}
+
+fn reactions_summary(message: &MessageEvent) -> Option<String> {
+    if message.reactions.is_empty() {
+        return None;
+    }
+    let groups: Vec<String> = message
+        .reactions
+        .iter()
+        .map(|group| format!("{} {}", group.key, group.count))
+        .collect();
+    Some(groups.join(", "))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; max-width: 60em; margin: 2em auto; color: #222; }
+.message { padding: 0.4em 0; border-bottom: 1px solid #eee; }
+.meta { color: #666; font-size: 0.85em; }
+.sender { font-weight: bold; color: #225; }
+.reference, .reactions { color: #666; font-size: 0.85em; margin-left: 1em; }
+.body.plain { white-space: pre-wrap; }
+.emote .body { font-style: italic; }
+blockquote { border-left: 3px solid #ccc; margin: 0.3em 0; padding-left: 0.8em; color: #555; }
+pre, code { background: #f4f4f4; }";
+
+/// Arguments of the `export` command line subcommand
+pub struct ExportArgs {
+    pub room_id: String,
+    pub format: ExportFormat,
+    pub range: DateRange,
+    /// File to write to, standard output if `None`
+    pub output: Option<String>,
+    /// Account to export with, the first one if `None`
+    pub account: Option<String>,
+}
+
+pub const EXPORT_USAGE: &str = "Usage: matrix-web export <room-id> [--format jsonl|html|txt] [--since DATE] [--until DATE] [--output FILE] [--account ID]
+
+Exports the full history of a configured room, decrypted, to standard output
+or FILE. DATE is YYYY-MM-DD (UTC, `--until` includes the whole day) or an
+RFC 3339 timestamp. The database password is read from DATABASE_PASSWORD or
+prompted for; the web interface must have logged in at least once, and must
+be stopped while exporting since both use the same device. ID selects the
+account, by default the first one.";
+
+impl ExportArgs {
+    /// Parse the arguments following `export`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut room_id = None;
+        let mut format = ExportFormat::JsonLines;
+        let mut since = None;
+        let mut until = None;
+        let mut output = None;
+        let mut account = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().cloned().with_context(|| format!("Missing value for {}", name));
+            match arg.as_str() {
+                "--format" | "-f" => format = value(arg)?.parse()?,
+                "--since" => since = Some(value(arg)?),
+                "--until" => until = Some(value(arg)?),
+                "--output" | "-o" => output = Some(value(arg)?),
+                "--account" => account = Some(value(arg)?),
+                _ if arg.starts_with('-') => bail!("Unknown option: {}", arg),
+                _ if room_id.is_none() => room_id = Some(arg.clone()),
+                _ => bail!("Unexpected argument: {}", arg),
+            }
+        }
+
+        Ok(Self {
+            room_id: room_id.context("Missing room ID")?,
+            format,
+            range: DateRange::parse(since.as_deref(), until.as_deref())?,
+            output,
+            account,
+        })
+    }
+}
+
+/// Run the `export` subcommand: restore the stored session without syncing,
+/// export the room and disconnect again. The caller must hold the store lock.
+pub async fn run_cli(bot: &MatrixBot, credentials_store: &CredentialStore, args: ExportArgs) -> Result<()> {
+    if !bot.has_room(&args.room_id) {
+        bail!("Room not configured: {}", args.room_id);
+    }
+    if !credentials_store.session_exists()? {
+        bail!("No stored session, log in through the web interface first");
+    }
+
+    let db_password = match std::env::var("DATABASE_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => rpassword::prompt_password("Database password: ")?,
+    };
+    bot.open_session(&db_password, credentials_store).await?;
+
+    let result = async {
+        let export = RoomExport::fetch(bot, &args.room_id, args.range).await?;
+        match &args.output {
+            Some(path) => {
+                let file = std::fs::File::create(path).with_context(|| format!("Failed to create {}", path))?;
+                export.write(&mut io::BufWriter::new(file), args.format)?;
+            }
+            None => export.write(&mut io::stdout().lock(), args.format)?,
+        }
+        anyhow::Ok(export.messages.len())
+    }
+    .await;
+
+    bot.disconnect(credentials_store).await?;
+
+    let count = result?;
+    eprintln!("Exported {} messages from {}", count, args.room_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<ExportArgs> {
+        ExportArgs::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    fn export(messages: Vec<MessageEvent>) -> RoomExport {
+        RoomExport {
+            room_id: "!room:example.org".to_string(),
+            room_name: Some("<b>Room</b>".to_string()),
+            messages,
+        }
+    }
+
+    fn written(export: &RoomExport, format: ExportFormat) -> String {
+        let mut out = Vec::new();
+        export.write(&mut out, format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parses_export_arguments() {
+        let parsed = args(&["!room:example.org", "--format", "html", "--since", "2024-01-01", "-o", "out.html", "--account", "work"]).unwrap();
+        assert_eq!(parsed.room_id, "!room:example.org");
+        assert_eq!(parsed.format, ExportFormat::Html);
+        assert!(parsed.range.since.is_some());
+        assert_eq!(parsed.range.until, None);
+        assert_eq!(parsed.output.as_deref(), Some("out.html"));
+        assert_eq!(parsed.account.as_deref(), Some("work"));
+
+        let defaults = args(&["!room:example.org"]).unwrap();
+        assert_eq!(defaults.format, ExportFormat::JsonLines);
+        assert!(defaults.output.is_none() && defaults.account.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_export_arguments() {
+        assert!(args(&[]).is_err());
+        assert!(args(&["!a:example.org", "!b:example.org"]).is_err());
+        assert!(args(&["!room:example.org", "--format", "pdf"]).is_err());
+        assert!(args(&["!room:example.org", "--output"]).is_err());
+        assert!(args(&["!room:example.org", "--verbose"]).is_err());
+    }
+
+    #[test]
+    fn until_date_includes_the_whole_day() {
+        let range = DateRange::parse(Some("2024-03-01"), Some("2024-03-01")).unwrap();
+        let start = DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z").unwrap().timestamp_millis() as u64;
+        let end = DateTime::parse_from_rfc3339("2024-03-02T00:00:00Z").unwrap().timestamp_millis() as u64;
+
+        assert!(!range.contains(start - 1));
+        assert!(range.contains(start));
+        assert!(range.contains(end - 1));
+        assert!(!range.contains(end));
+    }
+
+    #[test]
+    fn parses_timestamps_and_rejects_empty_ranges() {
+        let range = DateRange::parse(Some("2024-03-01T12:00:00+02:00"), None).unwrap();
+        assert_eq!(range.since, Some(DateTime::parse_from_rfc3339("2024-03-01T10:00:00Z").unwrap().timestamp_millis() as u64));
+
+        assert!(DateRange::parse(Some("2024-03-02"), Some("2024-03-01")).is_err());
+        assert!(DateRange::parse(Some("yesterday"), None).is_err());
+        assert!(DateRange::parse(Some("1969-12-31"), None).is_err());
+        assert!(DateRange::default().contains(0));
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape_html(r#"<a href="x">'&'</a>"#), "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+    }
+
+    #[test]
+    fn html_transcript_escapes_plain_text() {
+        let mut message = MessageEvent::text("$1", "!room:example.org", "@eve:example.org", "<script>alert(1)</script>");
+        message.sender_display_name = Some("<img src=x onerror=alert(1)>".to_string());
+        let html = written(&export(vec![message]), ExportFormat::Html);
+
+        assert!(html.contains("<title>&lt;b&gt;Room&lt;/b&gt;</title>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(html.contains("&lt;img src=x onerror=alert(1)&gt;"));
+        assert!(!html.contains("<script>") && !html.contains("<img"));
+    }
+
+    #[test]
+    fn writes_one_json_message_per_line() {
+        let messages = vec![
+            MessageEvent::text("$1", "!room:example.org", "@alice:example.org", "one"),
+            MessageEvent::text("$2", "!room:example.org", "@alice:example.org", "two\nlines"),
+        ];
+        let jsonl = written(&export(messages), ExportFormat::JsonLines);
+
+        let lines: Vec<_> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let second: MessageEvent = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second.body, "two\nlines");
+    }
+}
@@ -1,15 +1,69 @@
+mod archive;
 mod bot;
 mod config;
 mod credentials;
+mod db;
+mod export;
+mod media;
+mod messages;
+mod passphrase;
+mod recovery;
+mod store_lock;
+mod timeline;
+mod verification;
 mod web;
 
-use config::Config;
+use archive::MessageArchive;
+use config::{Account, Config};
 use credentials::CredentialStore;
+use export::{ExportArgs, EXPORT_USAGE};
+use passphrase::CHANGE_PASSPHRASE_USAGE;
+use store_lock::StoreLock;
+
+/// What to run. Subcommands run once and exit instead of starting the web server.
+enum Command {
+    Serve,
+    Export(ExportArgs),
+    ChangePassphrase { account: Option<String> },
+}
+
+impl Command {
+    fn from_args(args: &[String]) -> Self {
+        match args.get(1).map(String::as_str) {
+            None => Command::Serve,
+            Some("export") => Command::Export(ExportArgs::parse(&args[2..]).unwrap_or_else(|e| {
+                eprintln!("{}\n\n{}", e, EXPORT_USAGE);
+                std::process::exit(2);
+            })),
+            Some("change-passphrase") => match &args[2..] {
+                [] => Command::ChangePassphrase { account: None },
+                [flag, account] if flag == "--account" => Command::ChangePassphrase {
+                    account: Some(account.clone()),
+                },
+                _ => {
+                    eprintln!("{}", CHANGE_PASSPHRASE_USAGE);
+                    std::process::exit(2);
+                }
+            },
+            Some(command) => {
+                eprintln!("Unknown command: {}\n\n{}\n\n{}", command, EXPORT_USAGE, CHANGE_PASSPHRASE_USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    let args: Vec<String> = std::env::args().collect();
+    let command = Command::from_args(&args);
+
+    // Initialize logging, on stderr for subcommands that may write to stdout
+    if matches!(command, Command::Serve) {
+        tracing_subscriber::fmt::init();
+    } else {
+        tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    }
 
     // Load configuration
     let (config, _config_path) = Config::load_from_default_locations().unwrap_or_else(|e| {
@@ -18,31 +72,106 @@ async fn main() -> anyhow::Result<()> {
         std::process::exit(1);
     });
 
-    // Create Matrix bot (not connected yet)
-    let (bot, _) = bot::MatrixBot::new(
-        &config.homeserver,
-        &config.username,
-        &config.room_id,
-        config.message_history.limit,
-        &config.store.path,
-    );
+    let accounts = config.accounts().unwrap_or_else(|e| {
+        eprintln!("Invalid accounts in config file: {}", e);
+        std::process::exit(1);
+    });
+    if let Some(account) = accounts.iter().find(|a| a.room_ids.is_empty()) {
+        eprintln!(
+            "No rooms configured for account {}. Please set `rooms` (or `room_id`) in your config file",
+            account.id
+        );
+        std::process::exit(1);
+    }
 
-    // Clone bot for web server
-    let bot_for_web = bot.clone();
+    // A passphrase change cut short leaves values under both passphrases
+    for account in &accounts {
+        let credentials_store = CredentialStore::new(&config.database.path, &account.credentials_id);
+        if let Err(e) = passphrase::finish_interrupted_change(&credentials_store) {
+            eprintln!("Failed to finish the interrupted passphrase change of account {}: {:#}", account.id, e);
+            std::process::exit(1);
+        }
+    }
 
-    // Create credential store
-    let credentials_store = CredentialStore::new(&config.database.path);
+    // Searchable archive of decrypted messages, kept alongside the credentials
+    let archive = match config.archive.enabled.then(|| MessageArchive::open(&config.database.path)).transpose() {
+        Ok(archive) => archive,
+        Err(e) => {
+            eprintln!("Failed to open the message archive: {:#}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Start web server
-    let auth_config = config.web.auth.clone();
-    let state = web::AppState {
-        bot: bot_for_web,
-        auth: auth_config,
-        credentials_store,
-        username: config.username.clone(),
+    // Create a Matrix bot (not connected yet) and credential store per account
+    let create_bot = |account: &Account| {
+        let (bot, _) = bot::MatrixBot::new(
+            &account.homeserver,
+            &account.username,
+            account.room_ids.clone(),
+            &config.message_history,
+            &account.store_path,
+            config.media.max_size,
+            archive.clone(),
+        );
+        (bot, CredentialStore::new(&config.database.path, &account.credentials_id))
     };
+
+    match command {
+        Command::Serve => {}
+        Command::Export(export_args) => {
+            let account = select_account(&accounts, export_args.account.as_deref());
+            let _lock = lock_store(account);
+            let (bot, credentials_store) = create_bot(account);
+            return export::run_cli(&bot, &credentials_store, export_args).await;
+        }
+        Command::ChangePassphrase { account } => {
+            let account = select_account(&accounts, account.as_deref());
+            let _lock = lock_store(account);
+            let credentials_store = CredentialStore::new(&config.database.path, &account.credentials_id);
+            return passphrase::run_cli(&credentials_store, &account.store_path);
+        }
+    }
+
+    // Held until the server stops, so subcommands can't use the stores meanwhile
+    let _locks: Vec<StoreLock> = accounts.iter().map(lock_store).collect();
+
+    // Start web server
+    let states = accounts
+        .iter()
+        .map(|account| {
+            let (bot, credentials_store) = create_bot(account);
+            web::AppState {
+                account_id: account.id.clone(),
+                bot,
+                credentials_store,
+                archive: archive.clone(),
+                username: account.username.clone(),
+                pending_logout: Default::default(),
+                pending_sso: Default::default(),
+            }
+        })
+        .collect();
     
-    web::start_server(&config.web.host, config.web.port, state).await?;
+    web::start_server(&config.web.host, config.web.port, states, config.web.auth.clone()).await?;
 
     Ok(())
 }
+
+/// Take the lock on an account's Matrix store, or exit if another process has it
+fn lock_store(account: &Account) -> StoreLock {
+    StoreLock::acquire(&account.store_path).unwrap_or_else(|e| {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    })
+}
+
+/// The account a subcommand works on: the one named, or the first
+fn select_account<'a>(accounts: &'a [Account], id: Option<&str>) -> &'a Account {
+    match id {
+        None => &accounts[0],
+        Some(id) => accounts.iter().find(|a| a.id == id).unwrap_or_else(|| {
+            eprintln!("Unknown account: {}", id);
+            std::process::exit(2);
+        }),
+    }
+}
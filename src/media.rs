@@ -0,0 +1,284 @@
+use matrix_sdk::bytes::Bytes;
+use matrix_sdk::crypto::{AttachmentDecryptor, MediaEncryptionInfo};
+use matrix_sdk::ruma::events::room::{message::MessageType, MediaSource};
+use matrix_sdk::reqwest::{self, Url};
+use matrix_sdk::Client;
+use std::fmt;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+/// Content types that are safe to display inline from our own origin
+const INLINE_CONTENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Size of the chunks decrypted attachments are streamed in
+const DECRYPT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long the homeserver may take to connect, to answer a download, or to
+/// send its next chunk
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where to fetch an attachment from, as found in its message event
+pub struct MediaSources {
+    pub source: MediaSource,
+    pub thumbnail_source: Option<MediaSource>,
+    pub mimetype: Option<String>,
+    pub thumbnail_mimetype: Option<String>,
+    pub size: Option<u64>,
+    pub filename: String,
+}
+
+impl MediaSources {
+    /// Extract the media sources of an image, file, audio or video message
+    pub fn from_msgtype(msgtype: &MessageType) -> Option<Self> {
+        match msgtype {
+            MessageType::Image(image) => {
+                let info = image.info.as_deref();
+                Some(Self {
+                    source: image.source.clone(),
+                    thumbnail_source: info.and_then(|i| i.thumbnail_source.clone()),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    thumbnail_mimetype: info
+                        .and_then(|i| i.thumbnail_info.as_ref())
+                        .and_then(|t| t.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    filename: image.body.clone(),
+                })
+            }
+            MessageType::File(file) => {
+                let info = file.info.as_deref();
+                Some(Self {
+                    source: file.source.clone(),
+                    thumbnail_source: info.and_then(|i| i.thumbnail_source.clone()),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    thumbnail_mimetype: info
+                        .and_then(|i| i.thumbnail_info.as_ref())
+                        .and_then(|t| t.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    filename: file.filename.clone().unwrap_or_else(|| file.body.clone()),
+                })
+            }
+            MessageType::Audio(audio) => {
+                let info = audio.info.as_deref();
+                Some(Self {
+                    source: audio.source.clone(),
+                    thumbnail_source: None,
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    thumbnail_mimetype: None,
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    filename: audio.body.clone(),
+                })
+            }
+            MessageType::Video(video) => {
+                let info = video.info.as_deref();
+                Some(Self {
+                    source: video.source.clone(),
+                    thumbnail_source: info.and_then(|i| i.thumbnail_source.clone()),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    thumbnail_mimetype: info
+                        .and_then(|i| i.thumbnail_info.as_ref())
+                        .and_then(|t| t.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    filename: video.body.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Dimensions of an uploaded attachment, as measured by the browser
+#[derive(Debug, Default)]
+pub struct AttachmentDimensions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_ms: Option<u64>,
+}
+
+/// An attachment's content, in chunks as it is downloaded and decrypted
+pub type MediaStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// An attachment being downloaded, ready to be streamed to the browser
+pub struct MediaDownload {
+    pub body: MediaStream,
+    pub content_type: String,
+    pub filename: String,
+}
+
+impl MediaDownload {
+    /// Whether the browser may render this content inline.
+    ///
+    /// Everything else is served as a download so user-supplied HTML or SVG
+    /// never runs in the web UI's origin.
+    pub fn is_inline_safe(&self) -> bool {
+        INLINE_CONTENT_TYPES.contains(&self.content_type.as_str())
+            || self.content_type.starts_with("audio/")
+            || self.content_type.starts_with("video/")
+    }
+
+    /// The filename reduced to characters that are safe in a Content-Disposition header
+    pub fn header_safe_filename(&self) -> String {
+        self.filename
+            .chars()
+            .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+    /// No event with this ID was found in any configured room
+    NotFound(String),
+    /// The event exists but has no attachment
+    NotMedia(String),
+    /// The attachment exceeds the configured size limit
+    TooLarge { size: u64, limit: u64 },
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::NotFound(event_id) => write!(f, "Event not found: {}", event_id),
+            MediaError::NotMedia(event_id) => write!(f, "Event has no attachment: {}", event_id),
+            MediaError::TooLarge { size, limit } => {
+                write!(f, "Attachment is {} bytes, larger than the {} byte limit", size, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+/// The HTTP client of an account, handed to the SDK and used for media
+/// downloads, so both share one connection pool and configuration.
+///
+/// It has no overall timeout: the SDK sets one on each of its requests, and
+/// downloads time out per chunk instead, since large files take a while.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(concat!("matrix-web/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("Failed to initialize the HTTP client")
+}
+
+/// Download an attachment from the homeserver, yielding it as it arrives and
+/// decrypting encrypted files on the way. The download stops with
+/// `MediaError::TooLarge` as soon as more than `limit` bytes have arrived.
+///
+/// The SDK only downloads media into memory, so the request is made here,
+/// with the account's `http` client and access token.
+///
+/// An encrypted file's hash can only be checked at its end, so a file that
+/// fails the check is cut off with an error after most of it was sent.
+pub async fn download(client: &Client, http: &reqwest::Client, source: &MediaSource, limit: u64) -> anyhow::Result<MediaStream> {
+    let uri = match source {
+        MediaSource::Plain(uri) => uri,
+        MediaSource::Encrypted(file) => &file.url,
+    };
+    let (server_name, media_id) = uri.parts()?;
+
+    let mut request = http.get(download_url(client.homeserver(), server_name.as_str(), media_id)?);
+    if let Some(access_token) = client.access_token() {
+        request = request.bearer_auth(access_token);
+    }
+    let response = tokio::time::timeout(HTTP_TIMEOUT, request.send())
+        .await
+        .map_err(|_| anyhow::anyhow!("The homeserver didn't answer the media download in time"))??
+        .error_for_status()?;
+    if let Some(size) = response.content_length().filter(|size| *size > limit) {
+        return Err(MediaError::TooLarge { size, limit }.into());
+    }
+
+    let mut received = 0u64;
+    let body = response.bytes_stream().timeout(HTTP_TIMEOUT).map(move |chunk| {
+        let chunk = chunk
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "The homeserver stopped sending the media"))?
+            .map_err(io::Error::other)?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(io::Error::other(MediaError::TooLarge { size: received, limit }));
+        }
+        Ok(chunk)
+    });
+
+    Ok(match source {
+        MediaSource::Plain(_) => Box::pin(body),
+        MediaSource::Encrypted(file) => decrypt(Box::pin(body), file.as_ref().clone().into()),
+    })
+}
+
+/// The download endpoint of a media ID, under the homeserver's path (which
+/// `Url::join` would replace the last segment of without a trailing slash)
+fn download_url(homeserver: Url, server_name: &str, media_id: &str) -> anyhow::Result<Url> {
+    let mut url = homeserver.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid homeserver URL: {}", homeserver))?
+        .pop_if_empty()
+        .extend(["_matrix", "media", "v3", "download", server_name, media_id]);
+    Ok(url)
+}
+
+/// Decrypt an attachment chunk by chunk, on a blocking thread since the
+/// decryptor reads synchronously
+fn decrypt(body: MediaStream, info: MediaEncryptionInfo) -> MediaStream {
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let mut reader = SyncIoBridge::new(StreamReader::new(body));
+
+    tokio::task::spawn_blocking(move || {
+        let mut decryptor = match AttachmentDecryptor::new(&mut reader, info) {
+            Ok(decryptor) => decryptor,
+            Err(e) => {
+                let _ = tx.blocking_send(Err(io::Error::other(e)));
+                return;
+            }
+        };
+
+        let mut buf = vec![0; DECRYPT_CHUNK_SIZE];
+        loop {
+            let chunk = match decryptor.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => Ok(Bytes::copy_from_slice(&buf[..n])),
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            // Stop when the browser went away or the download failed
+            if tx.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    Box::pin(ReceiverStream::new(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(homeserver: &str, media_id: &str) -> String {
+        download_url(Url::parse(homeserver).unwrap(), "example.org", media_id).unwrap().to_string()
+    }
+
+    #[test]
+    fn download_url_keeps_the_homeserver_path() {
+        assert_eq!(url("https://example.org", "abc"), "https://example.org/_matrix/media/v3/download/example.org/abc");
+        assert_eq!(url("https://example.org/", "abc"), "https://example.org/_matrix/media/v3/download/example.org/abc");
+        assert_eq!(
+            url("https://example.org/matrix", "abc"),
+            "https://example.org/matrix/_matrix/media/v3/download/example.org/abc"
+        );
+        assert_eq!(
+            url("https://example.org/matrix/", "abc"),
+            "https://example.org/matrix/_matrix/media/v3/download/example.org/abc"
+        );
+    }
+
+    #[test]
+    fn download_url_escapes_the_media_id() {
+        assert_eq!(url("https://example.org", "a/b?c"), "https://example.org/_matrix/media/v3/download/example.org/a%2Fb%3Fc");
+    }
+}
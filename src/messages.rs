@@ -0,0 +1,771 @@
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::{
+            relation::Replacement,
+            room::{
+                encrypted::{EncryptedEventScheme, OriginalSyncRoomEncryptedEvent, RoomEncryptedEventContent},
+                message::{
+                    FormattedBody, MessageFormat, MessageType, Relation, RoomMessageEventContent,
+                    RoomMessageEventContentWithoutRelation,
+                },
+                MediaSource,
+            },
+        },
+        serde::Raw,
+        EventId, MilliSecondsSinceUnixEpoch, UserId,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use tracing::warn;
+
+/// HTML tags allowed in formatted bodies, following the Matrix client-server spec.
+/// `img` is left out because browsers can't load `mxc://` sources.
+const ALLOWED_HTML_TAGS: &[&str] = &[
+    "font", "del", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "p", "a", "ul", "ol",
+    "sup", "sub", "li", "b", "i", "u", "strong", "em", "strike", "code", "hr", "br", "div",
+    "table", "thead", "tbody", "tr", "th", "td", "caption", "pre", "span", "details", "summary",
+];
+
+const ALLOWED_URL_SCHEMES: &[&str] = &["https", "http", "ftp", "mailto", "magnet"];
+
+/// `msgtype` of the placeholder shown for an encrypted event the bot has no
+/// key for, as used by Element
+pub const UNDECRYPTABLE_MSGTYPE: &str = "m.bad.encrypted";
+
+/// The `m.relates_to` information of a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "rel_type", rename_all = "snake_case")]
+pub enum MessageRelation {
+    Reply {
+        event_id: String,
+    },
+    Thread {
+        event_id: String,
+        in_reply_to: Option<String>,
+        is_falling_back: bool,
+    },
+    Replacement {
+        event_id: String,
+    },
+}
+
+/// Attachment details of an image, file, audio or video message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    pub filename: String,
+    pub mimetype: Option<String>,
+    pub size: Option<u64>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub duration_ms: Option<u64>,
+    /// Whether the file is end-to-end encrypted and must be decrypted before display
+    pub encrypted: bool,
+    pub has_thumbnail: bool,
+}
+
+/// A room message as delivered to web clients by the history and stream endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEvent {
+    pub event_id: String,
+    pub room_id: String,
+    pub sender: String,
+    pub sender_display_name: Option<String>,
+    /// Origin server timestamp in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub msgtype: String,
+    pub body: String,
+    /// Sanitized HTML body, only set when the message uses `org.matrix.custom.html`
+    pub formatted_body: Option<String>,
+    pub relates_to: Option<MessageRelation>,
+    /// The message this one replies to, excluding thread fallbacks
+    pub in_reply_to: Option<String>,
+    /// The root event of the thread this message belongs to
+    pub thread_root: Option<String>,
+    /// Set for `m.image`, `m.file`, `m.audio` and `m.video` messages
+    pub attachment: Option<AttachmentInfo>,
+    /// Set for `m.location` messages
+    pub geo_uri: Option<String>,
+    /// Whether the content has been replaced by an edit
+    pub edited: bool,
+    /// `m.reaction` annotations, grouped by key in order of first use
+    pub reactions: Vec<ReactionGroup>,
+    /// The encrypted event behind an undecryptable placeholder
+    #[serde(skip)]
+    pub encrypted: Option<EncryptedSource>,
+}
+
+/// An encrypted event kept with its placeholder, so it can be decrypted
+/// locally once its room key arrives
+#[derive(Debug, Clone)]
+pub struct EncryptedSource {
+    /// The Megolm session whose key is missing
+    pub session_id: String,
+    pub event: Raw<OriginalSyncRoomEncryptedEvent>,
+}
+
+impl EncryptedSource {
+    /// `None` for encryption schemes other than Megolm
+    pub fn new(event: Raw<OriginalSyncRoomEncryptedEvent>, content: &RoomEncryptedEventContent) -> Option<Self> {
+        match &content.scheme {
+            EncryptedEventScheme::MegolmV1AesSha2(megolm) => Some(Self {
+                session_id: megolm.session_id.clone(),
+                event,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// All reactions to a message with the same key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionGroup {
+    pub key: String,
+    pub count: usize,
+    pub senders: Vec<ReactionSender>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionSender {
+    pub user_id: String,
+    /// The `m.reaction` event, needed to take the reaction back
+    pub event_id: String,
+}
+
+/// The current reactions of a message, pushed whenever they change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionUpdate {
+    pub room_id: String,
+    pub event_id: String,
+    pub reactions: Vec<ReactionGroup>,
+}
+
+impl From<&MessageEvent> for ReactionUpdate {
+    fn from(message: &MessageEvent) -> Self {
+        Self {
+            room_id: message.room_id.clone(),
+            event_id: message.event_id.clone(),
+            reactions: message.reactions.clone(),
+        }
+    }
+}
+
+/// A message that was removed by an `m.room.redaction` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Redaction {
+    pub room_id: String,
+    /// The redacted event
+    pub event_id: String,
+    pub reason: Option<String>,
+}
+
+/// A room whose cached history was loaded again, after room keys arrived
+/// that may decrypt messages it couldn't show before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryReload {
+    pub room_id: String,
+}
+
+/// A change to a room's timeline, as pushed to stream subscribers
+#[derive(Debug, Clone)]
+pub enum RoomUpdate {
+    /// A new message
+    Message(MessageEvent),
+    /// A message whose content was replaced by an edit
+    Edit(MessageEvent),
+    /// A redacted event, to be removed from the timeline
+    Redaction(Redaction),
+    /// The reactions of a message changed
+    Reactions(ReactionUpdate),
+    /// The room's history should be fetched again
+    History(HistoryReload),
+    /// An undecryptable message whose key arrived, replacing its placeholder
+    Decrypted(MessageEvent),
+}
+
+impl RoomUpdate {
+    pub fn room_id(&self) -> &str {
+        match self {
+            Self::Message(message) | Self::Edit(message) | Self::Decrypted(message) => &message.room_id,
+            Self::Redaction(redaction) => &redaction.room_id,
+            Self::Reactions(update) => &update.room_id,
+            Self::History(reload) => &reload.room_id,
+        }
+    }
+}
+
+impl MessageEvent {
+    /// Build a message event from the parts of an original room message.
+    ///
+    /// Returns `None` for message types that are not displayed.
+    pub async fn from_parts(
+        room: &Room,
+        event_id: &EventId,
+        sender: &UserId,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        content: &RoomMessageEventContent,
+    ) -> Option<Self> {
+        let mut formatted_body = None;
+        let mut attachment = None;
+        let mut geo_uri = None;
+
+        match &content.msgtype {
+            MessageType::Text(text) => formatted_body = html_body(text.formatted.as_ref()),
+            MessageType::Notice(notice) => formatted_body = html_body(notice.formatted.as_ref()),
+            MessageType::Emote(emote) => formatted_body = html_body(emote.formatted.as_ref()),
+            MessageType::Image(image) => {
+                let info = image.info.as_deref();
+                attachment = Some(AttachmentInfo {
+                    filename: image.body.clone(),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    width: info.and_then(|i| i.width).map(u64::from),
+                    height: info.and_then(|i| i.height).map(u64::from),
+                    duration_ms: None,
+                    encrypted: is_encrypted(&image.source),
+                    has_thumbnail: info.is_some_and(|i| i.thumbnail_source.is_some()),
+                });
+            }
+            MessageType::File(file) => {
+                let info = file.info.as_deref();
+                attachment = Some(AttachmentInfo {
+                    filename: file.filename.clone().unwrap_or_else(|| file.body.clone()),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    width: None,
+                    height: None,
+                    duration_ms: None,
+                    encrypted: is_encrypted(&file.source),
+                    has_thumbnail: info.is_some_and(|i| i.thumbnail_source.is_some()),
+                });
+            }
+            MessageType::Audio(audio) => {
+                let info = audio.info.as_deref();
+                attachment = Some(AttachmentInfo {
+                    filename: audio.body.clone(),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    width: None,
+                    height: None,
+                    duration_ms: info.and_then(|i| i.duration).map(|d| d.as_millis() as u64),
+                    encrypted: is_encrypted(&audio.source),
+                    has_thumbnail: false,
+                });
+            }
+            MessageType::Video(video) => {
+                let info = video.info.as_deref();
+                attachment = Some(AttachmentInfo {
+                    filename: video.body.clone(),
+                    mimetype: info.and_then(|i| i.mimetype.clone()),
+                    size: info.and_then(|i| i.size).map(u64::from),
+                    width: info.and_then(|i| i.width).map(u64::from),
+                    height: info.and_then(|i| i.height).map(u64::from),
+                    duration_ms: info.and_then(|i| i.duration).map(|d| d.as_millis() as u64),
+                    encrypted: is_encrypted(&video.source),
+                    has_thumbnail: info.is_some_and(|i| i.thumbnail_source.is_some()),
+                });
+            }
+            MessageType::Location(location) => geo_uri = Some(location.geo_uri.clone()),
+            // In-room verification requests are handled by the verification flow
+            MessageType::VerificationRequest(_) => return None,
+            // Server notices and custom msgtypes are shown by their plain body
+            _ => {}
+        }
+
+        let relates_to = content.relates_to.as_ref().and_then(MessageRelation::from_relation);
+        let in_reply_to = relates_to.as_ref().and_then(MessageRelation::reply_target);
+        let thread_root = relates_to.as_ref().and_then(MessageRelation::thread_root);
+
+        // Replies carry a quoted fallback of the original for clients without
+        // reply support; we render quotes ourselves, so drop it.
+        let mut body = content.body().to_string();
+        if in_reply_to.is_some() {
+            body = strip_reply_fallback(&body);
+        }
+
+        Some(Self {
+            event_id: event_id.to_string(),
+            room_id: room.room_id().to_string(),
+            sender: sender.to_string(),
+            sender_display_name: sender_display_name(room, sender).await,
+            timestamp: origin_server_ts.0.into(),
+            msgtype: content.msgtype().to_string(),
+            body,
+            formatted_body,
+            relates_to,
+            in_reply_to,
+            thread_root,
+            attachment,
+            geo_uri,
+            edited: false,
+            reactions: Vec::new(),
+            encrypted: None,
+        })
+    }
+
+    /// Build the placeholder for an encrypted event that couldn't be decrypted
+    pub async fn undecryptable(
+        room: &Room,
+        event_id: &EventId,
+        sender: &UserId,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        encrypted: Option<EncryptedSource>,
+    ) -> Self {
+        Self {
+            event_id: event_id.to_string(),
+            room_id: room.room_id().to_string(),
+            sender: sender.to_string(),
+            sender_display_name: sender_display_name(room, sender).await,
+            timestamp: origin_server_ts.0.into(),
+            msgtype: UNDECRYPTABLE_MSGTYPE.to_string(),
+            body: "Unable to decrypt message".to_string(),
+            formatted_body: None,
+            relates_to: None,
+            in_reply_to: None,
+            thread_root: None,
+            attachment: None,
+            geo_uri: None,
+            edited: false,
+            reactions: Vec::new(),
+            encrypted,
+        }
+    }
+
+    /// Whether this is the placeholder of an event that couldn't be decrypted
+    pub fn is_undecryptable(&self) -> bool {
+        self.msgtype == UNDECRYPTABLE_MSGTYPE
+    }
+
+    /// Build the edited version of a message from an `m.replace` event.
+    ///
+    /// The result carries the ID of the message being edited.
+    pub async fn from_replacement(
+        room: &Room,
+        sender: &UserId,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        replacement: &Replacement<RoomMessageEventContentWithoutRelation>,
+    ) -> Option<Self> {
+        let content = replacement.new_content.clone().with_relation(None);
+        Self::from_parts(room, &replacement.event_id, sender, origin_server_ts, &content).await
+    }
+
+    /// Take over the content of an edit, keeping this message's identity and relations
+    fn apply_edit(&mut self, edit: MessageEvent) {
+        self.msgtype = edit.msgtype;
+        self.body = edit.body;
+        self.formatted_body = edit.formatted_body;
+        self.attachment = edit.attachment;
+        self.geo_uri = edit.geo_uri;
+        self.edited = true;
+    }
+
+    /// The `m.reaction` event `user_id` sent with `key`, if any
+    pub fn reaction_by(&self, key: &str, user_id: &str) -> Option<&str> {
+        self.reactions
+            .iter()
+            .find(|group| group.key == key)?
+            .senders
+            .iter()
+            .find(|sender| sender.user_id == user_id)
+            .map(|sender| sender.event_id.as_str())
+    }
+
+    /// Record a reaction, ignoring repeats of the same key by the same user
+    fn add_reaction(&mut self, key: &str, user_id: &str, reaction_event_id: &str) -> bool {
+        if self.reaction_by(key, user_id).is_some() {
+            return false;
+        }
+
+        let sender = ReactionSender {
+            user_id: user_id.to_string(),
+            event_id: reaction_event_id.to_string(),
+        };
+        match self.reactions.iter_mut().find(|group| group.key == key) {
+            Some(group) => {
+                group.senders.push(sender);
+                group.count = group.senders.len();
+            }
+            None => self.reactions.push(ReactionGroup {
+                key: key.to_string(),
+                count: 1,
+                senders: vec![sender],
+            }),
+        }
+        true
+    }
+
+    /// Remove a reaction by its event ID, dropping groups that become empty
+    fn remove_reaction(&mut self, reaction_event_id: &str) -> bool {
+        let mut removed = false;
+        for group in &mut self.reactions {
+            let before = group.senders.len();
+            group.senders.retain(|sender| sender.event_id != reaction_event_id);
+            group.count = group.senders.len();
+            removed |= group.count != before;
+        }
+        self.reactions.retain(|group| group.count > 0);
+        removed
+    }
+}
+
+#[cfg(test)]
+impl MessageEvent {
+    /// A plain `m.text` message for tests
+    pub(crate) fn text(event_id: &str, room_id: &str, sender: &str, body: &str) -> Self {
+        Self {
+            event_id: event_id.to_string(),
+            room_id: room_id.to_string(),
+            sender: sender.to_string(),
+            sender_display_name: None,
+            timestamp: 0,
+            msgtype: "m.text".to_string(),
+            body: body.to_string(),
+            formatted_body: None,
+            relates_to: None,
+            in_reply_to: None,
+            thread_root: None,
+            attachment: None,
+            geo_uri: None,
+            edited: false,
+            reactions: Vec::new(),
+            encrypted: None,
+        }
+    }
+}
+
+/// Apply an edit built by [`MessageEvent::from_replacement`] to the message it
+/// replaces. Edits by anyone but the original sender are ignored, as the spec
+/// requires.
+///
+/// Returns the updated message, or `None` if it isn't in `messages`.
+pub fn apply_edit(messages: &mut [MessageEvent], edit: MessageEvent) -> Option<MessageEvent> {
+    let message = messages
+        .iter_mut()
+        .find(|m| m.event_id == edit.event_id && m.sender == edit.sender)?;
+    message.apply_edit(edit);
+    Some(message.clone())
+}
+
+impl MessageRelation {
+    fn from_relation<C>(relation: &Relation<C>) -> Option<Self> {
+        match relation {
+            Relation::Reply { in_reply_to } => Some(Self::Reply {
+                event_id: in_reply_to.event_id.to_string(),
+            }),
+            Relation::Thread(thread) => Some(Self::Thread {
+                event_id: thread.event_id.to_string(),
+                in_reply_to: thread.in_reply_to.as_ref().map(|r| r.event_id.to_string()),
+                is_falling_back: thread.is_falling_back,
+            }),
+            Relation::Replacement(replacement) => Some(Self::Replacement {
+                event_id: replacement.event_id.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The event a message replies to, ignoring the `m.in_reply_to` that threads
+    /// only set as a fallback for clients without thread support
+    fn reply_target(&self) -> Option<String> {
+        match self {
+            Self::Reply { event_id } => Some(event_id.clone()),
+            Self::Thread { in_reply_to, is_falling_back: false, .. } => in_reply_to.clone(),
+            _ => None,
+        }
+    }
+
+    fn thread_root(&self) -> Option<String> {
+        match self {
+            Self::Thread { event_id, .. } => Some(event_id.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Summary of a thread found in a page of history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub root_event_id: String,
+    pub reply_count: usize,
+    pub latest_event_id: String,
+    pub latest_timestamp: u64,
+}
+
+/// Group the thread replies in `messages` by their thread root, in order of
+/// first appearance
+pub fn summarize_threads(messages: &[MessageEvent]) -> Vec<ThreadSummary> {
+    let mut threads: Vec<ThreadSummary> = Vec::new();
+    for message in messages {
+        let Some(root) = message.thread_root.as_ref() else {
+            continue;
+        };
+        match threads.iter_mut().find(|t| &t.root_event_id == root) {
+            Some(thread) => {
+                thread.reply_count += 1;
+                thread.latest_event_id = message.event_id.clone();
+                thread.latest_timestamp = message.timestamp;
+            }
+            None => threads.push(ThreadSummary {
+                root_event_id: root.clone(),
+                reply_count: 1,
+                latest_event_id: message.event_id.clone(),
+                latest_timestamp: message.timestamp,
+            }),
+        }
+    }
+    threads
+}
+
+/// Record an `m.reaction` annotation on the message it targets.
+///
+/// Returns the updated message, or `None` if it isn't in `messages` or the
+/// reaction was already recorded.
+pub fn apply_reaction(
+    messages: &mut [MessageEvent],
+    target_event_id: &str,
+    key: &str,
+    user_id: &str,
+    reaction_event_id: &str,
+) -> Option<MessageEvent> {
+    let message = messages.iter_mut().find(|m| m.event_id == target_event_id)?;
+    message
+        .add_reaction(key, user_id, reaction_event_id)
+        .then(|| message.clone())
+}
+
+/// Remove a redacted `m.reaction` from the message it was on.
+///
+/// Returns the updated message, or `None` if the event wasn't a known reaction.
+pub fn remove_reaction(messages: &mut [MessageEvent], reaction_event_id: &str) -> Option<MessageEvent> {
+    messages
+        .iter_mut()
+        .find_map(|m| m.remove_reaction(reaction_event_id).then(|| m.clone()))
+}
+
+/// Options for sending a text message
+#[derive(Debug, Default)]
+pub struct MessageOptions<'a> {
+    /// Render the body as Markdown into an HTML formatted body
+    pub markdown: bool,
+    pub reply_to_event_id: Option<&'a str>,
+    pub thread_root_event_id: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    /// No event with this ID was found in any configured room
+    NotFound(String),
+    /// The event exists but is not a room message
+    NotRoomMessage(String),
+    /// The message was sent by someone other than the bot
+    NotOwnMessage(String),
+    /// The message is not a text message and can't be edited
+    CannotEdit(String),
+    /// The bot has not reacted to the message with this key
+    ReactionNotFound { event_id: String, key: String },
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::NotFound(event_id) => write!(f, "Event not found: {}", event_id),
+            MessageError::NotRoomMessage(event_id) => write!(f, "Event is not a room message: {}", event_id),
+            MessageError::NotOwnMessage(event_id) => write!(f, "Message was not sent by the bot: {}", event_id),
+            MessageError::CannotEdit(event_id) => write!(f, "Only text messages can be edited: {}", event_id),
+            MessageError::ReactionNotFound { event_id, key } => {
+                write!(f, "No {} reaction from the bot on {}", key, event_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// The sanitized HTML body of a formatted message, ignoring other formats
+fn html_body(formatted: Option<&FormattedBody>) -> Option<String> {
+    formatted
+        .filter(|f| f.format == MessageFormat::Html)
+        .map(|f| sanitize_html(&f.body))
+}
+
+/// Reduce message HTML to the tags and attributes the Matrix spec allows,
+/// so it can be inserted into the web UI as-is.
+pub fn sanitize_html(html: &str) -> String {
+    ammonia::Builder::empty()
+        .add_tags(ALLOWED_HTML_TAGS)
+        .add_tag_attributes("a", &["href"])
+        .add_tag_attributes("ol", &["start"])
+        .add_tag_attributes("code", &["class"])
+        .add_tag_attributes("font", &["color", "data-mx-bg-color", "data-mx-color"])
+        .add_tag_attributes("span", &["data-mx-bg-color", "data-mx-color", "data-mx-spoiler"])
+        .url_schemes(ALLOWED_URL_SCHEMES.iter().copied().collect::<HashSet<_>>())
+        .link_rel(Some("noopener noreferrer nofollow"))
+        // Rich reply fallbacks quote the original message; drop them entirely,
+        // along with script and style bodies that would otherwise remain as text
+        .clean_content_tags(HashSet::from(["mx-reply", "script", "style"]))
+        .clean(html)
+        .to_string()
+}
+
+/// Remove the `> ` quoted lines (and the blank line after them) that rich
+/// replies prepend to the plain-text body
+fn strip_reply_fallback(body: &str) -> String {
+    if !body.starts_with("> ") {
+        return body.to_string();
+    }
+
+    let mut lines = body.lines().skip_while(|line| line.starts_with('>')).peekable();
+    if lines.peek().is_some_and(|line| line.is_empty()) {
+        lines.next();
+    }
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+fn is_encrypted(source: &MediaSource) -> bool {
+    matches!(source, MediaSource::Encrypted(_))
+}
+
+/// Look up the sender's display name from the locally known room members
+async fn sender_display_name(room: &Room, sender: &UserId) -> Option<String> {
+    match room.get_member_no_sync(sender).await {
+        Ok(member) => member.and_then(|m| m.display_name().map(str::to_owned)),
+        Err(e) => {
+            warn!("Failed to look up room member {}: {}", sender, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_strips_scripts_and_event_handlers() {
+        let html = sanitize_html(r#"<p onclick="alert(1)">hi</p><script>alert(2)</script><style>p {}</style><img src=x onerror="alert(3)"><iframe src="https://example.org"></iframe>"#);
+        assert_eq!(html, "<p>hi</p>");
+    }
+
+    #[test]
+    fn sanitize_drops_disallowed_url_schemes() {
+        let html = sanitize_html(r#"<a href="javascript:alert(1)">a</a><a href="data:text/html,x">b</a>"#);
+        assert!(!html.contains("javascript:") && !html.contains("data:"));
+        assert!(html.contains(">a</a>") && html.contains(">b</a>"));
+
+        let link = sanitize_html(r#"<a href="https://example.org/">c</a>"#);
+        assert_eq!(link, r#"<a href="https://example.org/" rel="noopener noreferrer nofollow">c</a>"#);
+    }
+
+    #[test]
+    fn sanitize_keeps_allowed_tags_and_attributes() {
+        let html = r#"<ol start="3"><li><strong>b</strong> <em>i</em></li></ol><pre><code class="language-rust">fn</code></pre><span data-mx-spoiler="">s</span><font color="red">c</font>"#;
+        assert_eq!(sanitize_html(html), html);
+
+        assert_eq!(sanitize_html(r#"<p style="color: red" class="x">p</p>"#), "<p>p</p>");
+    }
+
+    #[test]
+    fn sanitize_removes_reply_fallbacks() {
+        let html = sanitize_html(r#"<mx-reply><blockquote><a href="https://matrix.to/#/!r/$e">In reply to</a> quoted</blockquote></mx-reply>reply"#);
+        assert_eq!(html, "reply");
+    }
+
+    #[test]
+    fn strips_quoted_reply_fallback_from_the_body() {
+        assert_eq!(strip_reply_fallback("> <@alice:example.org> original\n> second line\n\nreply"), "reply");
+        assert_eq!(strip_reply_fallback("> only a quote"), "");
+        assert_eq!(strip_reply_fallback("not a reply\n> quote"), "not a reply\n> quote");
+    }
+
+    fn message(event_id: &str, sender: &str, body: &str) -> MessageEvent {
+        MessageEvent::text(event_id, "!room:example.org", sender, body)
+    }
+
+    #[test]
+    fn edit_replaces_the_content() {
+        let mut messages = vec![message("$1", "@alice:example.org", "helo")];
+        let mut edit = message("$1", "@alice:example.org", "hello");
+        edit.formatted_body = Some("<b>hello</b>".to_string());
+
+        let edited = apply_edit(&mut messages, edit).unwrap();
+        assert_eq!(edited.body, "hello");
+        assert!(edited.edited);
+        assert_eq!(messages[0].formatted_body.as_deref(), Some("<b>hello</b>"));
+    }
+
+    #[test]
+    fn edit_from_another_sender_is_ignored() {
+        let mut messages = vec![message("$1", "@alice:example.org", "original")];
+
+        assert!(apply_edit(&mut messages, message("$1", "@mallory:example.org", "forged")).is_none());
+        assert_eq!(messages[0].body, "original");
+        assert!(!messages[0].edited);
+    }
+
+    #[test]
+    fn later_edit_replaces_the_earlier_one() {
+        let mut messages = vec![message("$1", "@alice:example.org", "one")];
+        let mut first = message("$1", "@alice:example.org", "two");
+        first.formatted_body = Some("<i>two</i>".to_string());
+        apply_edit(&mut messages, first);
+        apply_edit(&mut messages, message("$1", "@alice:example.org", "three"));
+
+        assert_eq!(messages[0].body, "three");
+        assert_eq!(messages[0].formatted_body, None);
+    }
+
+    #[test]
+    fn edit_keeps_identity_and_relations() {
+        let mut original = message("$2", "@alice:example.org", "reply");
+        original.in_reply_to = Some("$1".to_string());
+        original.sender_display_name = Some("Alice".to_string());
+        original.timestamp = 42;
+        let mut messages = vec![original];
+
+        let edited = apply_edit(&mut messages, message("$2", "@alice:example.org", "edited reply")).unwrap();
+        assert_eq!(edited.in_reply_to.as_deref(), Some("$1"));
+        assert_eq!(edited.sender_display_name.as_deref(), Some("Alice"));
+        assert_eq!(edited.timestamp, 42);
+    }
+
+    #[test]
+    fn edit_of_an_unknown_event_is_a_no_op() {
+        let mut messages = vec![message("$1", "@alice:example.org", "original")];
+
+        assert!(apply_edit(&mut messages, message("$2", "@alice:example.org", "edit")).is_none());
+        assert_eq!(messages[0].body, "original");
+        assert!(!messages[0].edited);
+    }
+
+    #[test]
+    fn reactions_are_grouped_by_key_without_repeats() {
+        let mut messages = vec![message("$1", "@alice:example.org", "hi")];
+
+        assert!(apply_reaction(&mut messages, "$1", "👍", "@bob:example.org", "$r1").is_some());
+        assert!(apply_reaction(&mut messages, "$1", "👍", "@carol:example.org", "$r2").is_some());
+        assert!(apply_reaction(&mut messages, "$1", "🎉", "@bob:example.org", "$r3").is_some());
+        // The same key again from the same user isn't counted twice
+        assert!(apply_reaction(&mut messages, "$1", "👍", "@bob:example.org", "$r4").is_none());
+        assert!(apply_reaction(&mut messages, "$unknown", "👍", "@bob:example.org", "$r5").is_none());
+
+        let groups = &messages[0].reactions;
+        assert_eq!(groups.iter().map(|g| (g.key.as_str(), g.count)).collect::<Vec<_>>(), [("👍", 2), ("🎉", 1)]);
+        assert_eq!(messages[0].reaction_by("👍", "@carol:example.org"), Some("$r2"));
+        assert_eq!(messages[0].reaction_by("🎉", "@carol:example.org"), None);
+    }
+
+    #[test]
+    fn redacted_reactions_are_removed_with_empty_groups() {
+        let mut messages = vec![message("$1", "@alice:example.org", "hi")];
+        apply_reaction(&mut messages, "$1", "👍", "@bob:example.org", "$r1");
+        apply_reaction(&mut messages, "$1", "🎉", "@bob:example.org", "$r2");
+
+        let updated = remove_reaction(&mut messages, "$r2").unwrap();
+        assert_eq!(updated.reactions.len(), 1);
+        assert_eq!(updated.reactions[0].key, "👍");
+        assert!(remove_reaction(&mut messages, "$r2").is_none());
+        assert!(remove_reaction(&mut messages, "$1").is_none());
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::{Context, Result};
+use matrix_sdk_store_encryption::StoreCipher;
+use rusqlite::{Connection, OptionalExtension};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+use crate::credentials::{CredentialStore, StoredSecrets};
+
+/// Databases of the matrix-sdk SQLite store, which keep their store cipher
+/// wrapped with the passphrase
+pub(crate) const STORE_DATABASES: &[&str] = &["matrix-sdk-state.sqlite3", "matrix-sdk-crypto.sqlite3"];
+
+#[derive(Debug)]
+pub enum PassphraseError {
+    /// The current passphrase doesn't unlock the credentials or the store
+    Wrong(String),
+    /// The passphrase change can't be made as requested
+    Invalid(String),
+}
+
+impl fmt::Display for PassphraseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PassphraseError::Wrong(msg) => write!(f, "{}", msg),
+            PassphraseError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PassphraseError {}
+
+pub const CHANGE_PASSPHRASE_USAGE: &str = "Usage: matrix-web change-passphrase [--account ID]
+
+Re-encrypts the stored credentials and the Matrix store of an account, by
+default the first one, under a new database password. The current and new
+passwords are prompted for.";
+
+/// Journal target of the credentials row; store ciphers use their file's path
+const CREDENTIALS_TARGET: &str = "credentials";
+
+/// One value to re-encrypt, with what it replaces
+enum Change {
+    Credentials { old: StoredSecrets, new: StoredSecrets },
+    StoreCipher { path: PathBuf, old: Vec<u8>, new: Vec<u8> },
+}
+
+impl Change {
+    fn apply(&self, credentials_store: &CredentialStore) -> Result<()> {
+        match self {
+            Change::Credentials { old, new } => credentials_store.replace_secrets(old, new),
+            Change::StoreCipher { path, old, new } => replace_store_cipher(path, old, new),
+        }
+    }
+
+    fn revert(&self, credentials_store: &CredentialStore) -> Result<()> {
+        match self {
+            Change::Credentials { old, new } => credentials_store.replace_secrets(new, old),
+            Change::StoreCipher { path, old, new } => replace_store_cipher(path, new, old),
+        }
+    }
+
+    /// Bring the value to its new state, or its old one when `reverting`,
+    /// whichever of the two it is in now. Values deleted since are skipped.
+    fn settle(&self, credentials_store: &CredentialStore, reverting: bool) -> Result<()> {
+        match self {
+            Change::Credentials { old, new } => {
+                let (from, to) = if reverting { (new, old) } else { (old, new) };
+                match credentials_store.load_secrets()? {
+                    Some(current) if current == *from => credentials_store.replace_secrets(from, to),
+                    Some(current) if current != *to => {
+                        anyhow::bail!("The stored credentials match neither passphrase of the change")
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Change::StoreCipher { path, old, new } => {
+                let (from, to) = if reverting { (new, old) } else { (old, new) };
+                if !path.exists() {
+                    return Ok(());
+                }
+                match load_store_cipher(path)? {
+                    Some(current) if current == *from => replace_store_cipher(path, from, to),
+                    Some(current) if current != *to => {
+                        anyhow::bail!("The store cipher of {} matches neither passphrase of the change", path.display())
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Change::Credentials { .. } => "stored credentials".to_string(),
+            Change::StoreCipher { path, .. } => path.display().to_string(),
+        }
+    }
+
+    /// The journal record: target, old value and new value
+    fn to_record(&self) -> Result<(String, Vec<u8>, Vec<u8>)> {
+        Ok(match self {
+            Change::Credentials { old, new } => (
+                CREDENTIALS_TARGET.to_string(),
+                serde_json::to_vec(old)?,
+                serde_json::to_vec(new)?,
+            ),
+            Change::StoreCipher { path, old, new } => (path.to_string_lossy().into_owned(), old.clone(), new.clone()),
+        })
+    }
+
+    fn from_record(target: String, old: Vec<u8>, new: Vec<u8>) -> Result<Self> {
+        if target == CREDENTIALS_TARGET {
+            Ok(Change::Credentials {
+                old: serde_json::from_slice(&old)?,
+                new: serde_json::from_slice(&new)?,
+            })
+        } else {
+            Ok(Change::StoreCipher {
+                path: PathBuf::from(target),
+                old,
+                new,
+            })
+        }
+    }
+}
+
+/// Re-encrypt the stored credentials and the Matrix store under a new passphrase.
+///
+/// The store's data keys don't change: only the copy of its store cipher
+/// wrapped with the passphrase is replaced, so the session and crypto state
+/// are kept, and a running client is unaffected. Every value is re-encrypted
+/// and checked, then journaled with the value it replaces, before anything
+/// is written. Values already written are put back if a later write fails,
+/// and a change cut short by a crash is finished at the next start.
+pub fn rotate_passphrase(
+    credentials_store: &CredentialStore,
+    store_path: &str,
+    current_passphrase: &str,
+    new_passphrase: &str,
+) -> Result<()> {
+    if new_passphrase.is_empty() {
+        return Err(PassphraseError::Invalid("The new passphrase must not be empty".to_string()).into());
+    }
+    if new_passphrase == current_passphrase {
+        return Err(PassphraseError::Invalid("The new passphrase is the same as the current one".to_string()).into());
+    }
+
+    finish_interrupted_change(credentials_store)?;
+
+    let mut changes = Vec::new();
+
+    if let Some(secrets) = credentials_store.load_secrets()? {
+        let new = credentials_store
+            .reseal_secrets(&secrets, current_passphrase, new_passphrase)
+            .map_err(|e| PassphraseError::Wrong(e.to_string()))?;
+        changes.push(Change::Credentials { old: secrets, new });
+    }
+
+    for name in STORE_DATABASES {
+        let path = Path::new(store_path).join(name);
+        if !path.exists() {
+            continue;
+        }
+
+        let old = load_store_cipher(&path)?.ok_or_else(|| {
+            PassphraseError::Invalid(format!(
+                "{} was created without a passphrase and can't be encrypted in place",
+                path.display()
+            ))
+        })?;
+        let cipher = StoreCipher::import(current_passphrase, &old)
+            .map_err(|_| PassphraseError::Wrong("Wrong passphrase for the Matrix store".to_string()))?;
+        let new = cipher.export(new_passphrase).context("Failed to encrypt the store cipher")?;
+        changes.push(Change::StoreCipher { path, old, new });
+    }
+
+    if changes.is_empty() {
+        return Err(PassphraseError::Invalid("No stored credentials or Matrix store to re-encrypt".to_string()).into());
+    }
+
+    write_journal(credentials_store, &changes)?;
+
+    for (applied, change) in changes.iter().enumerate() {
+        if let Err(e) = change.apply(credentials_store) {
+            return Err(match roll_back(credentials_store, &changes[..applied]) {
+                Ok(()) => e.context(format!(
+                    "Failed to re-encrypt {}, passphrase change rolled back",
+                    change.describe()
+                )),
+                Err(undo) => {
+                    error!("Failed to roll back passphrase change: {:#}", undo);
+                    e.context(format!(
+                        "Failed to re-encrypt {}, the passphrase change is settled at the next start",
+                        change.describe()
+                    ))
+                }
+            });
+        }
+        info!("Re-encrypted {}", change.describe());
+    }
+
+    clear_journal(credentials_store)
+}
+
+/// Put back the values already written, journaling the rollback first
+fn roll_back(credentials_store: &CredentialStore, applied: &[Change]) -> Result<()> {
+    credentials_store.open_db()?.execute(
+        "UPDATE passphrase_changes SET reverting = 1 WHERE account = ?1",
+        [credentials_store.account()],
+    )?;
+    for change in applied.iter().rev() {
+        change
+            .revert(credentials_store)
+            .with_context(|| format!("Failed to roll back {}", change.describe()))?;
+    }
+    clear_journal(credentials_store)
+}
+
+/// Finish a passphrase change that was cut short, e.g. by a crash, or
+/// finish rolling it back. Every journaled value is brought to the same
+/// passphrase, so the credentials and the store can be opened again.
+pub fn finish_interrupted_change(credentials_store: &CredentialStore) -> Result<()> {
+    let conn = credentials_store.open_db()?;
+    let records = conn
+        .prepare("SELECT target, old_value, new_value, reverting FROM passphrase_changes WHERE account = ?1")?
+        .query_map([credentials_store.account()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(String, Vec<u8>, Vec<u8>, bool)>>>()?;
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let reverting = records.iter().any(|(_, _, _, reverting)| *reverting);
+    for (target, old, new, _) in records {
+        let change = Change::from_record(target, old, new)?;
+        change
+            .settle(credentials_store, reverting)
+            .with_context(|| format!("Failed to settle the interrupted passphrase change of {}", change.describe()))?;
+    }
+    clear_journal(credentials_store)?;
+
+    if reverting {
+        warn!("Rolled back an interrupted passphrase change, the old passphrase is still in use");
+    } else {
+        warn!("Finished an interrupted passphrase change, the new passphrase is now in use");
+    }
+    Ok(())
+}
+
+fn write_journal(credentials_store: &CredentialStore, changes: &[Change]) -> Result<()> {
+    let mut conn = credentials_store.open_db()?;
+    let tx = conn.transaction()?;
+    for change in changes {
+        let (target, old, new) = change.to_record()?;
+        tx.execute(
+            "INSERT INTO passphrase_changes (account, target, old_value, new_value) VALUES (?1, ?2, ?3, ?4)",
+            (credentials_store.account(), target, old, new),
+        )?;
+    }
+    tx.commit().context("Failed to journal the passphrase change")
+}
+
+fn clear_journal(credentials_store: &CredentialStore) -> Result<()> {
+    credentials_store.open_db()?.execute(
+        "DELETE FROM passphrase_changes WHERE account = ?1",
+        [credentials_store.account()],
+    )?;
+    Ok(())
+}
+
+fn load_store_cipher(path: &Path) -> Result<Option<Vec<u8>>> {
+    let conn = Connection::open(path)?;
+    conn.query_row("SELECT value FROM kv WHERE key = 'cipher'", [], |row| row.get(0))
+        .optional()
+        .with_context(|| format!("Failed to read the store cipher of {}", path.display()))
+}
+
+/// Replace the wrapped store cipher, provided it still is `expected`
+fn replace_store_cipher(path: &Path, expected: &[u8], cipher: &[u8]) -> Result<()> {
+    let conn = Connection::open(path)?;
+    let rows_affected = conn.execute(
+        "UPDATE kv SET value = ?1 WHERE key = 'cipher' AND value = ?2",
+        (cipher, expected),
+    )?;
+
+    if rows_affected == 0 {
+        anyhow::bail!("The store cipher of {} changed while it was being re-encrypted", path.display());
+    }
+
+    Ok(())
+}
+
+/// Run the `change-passphrase` subcommand
+pub fn run_cli(credentials_store: &CredentialStore, store_path: &str) -> Result<()> {
+    let current = rpassword::prompt_password("Current database password: ")?;
+    let new = rpassword::prompt_password("New database password: ")?;
+    if rpassword::prompt_password("Repeat new database password: ")? != new {
+        anyhow::bail!("The new passwords don't match");
+    }
+
+    rotate_passphrase(credentials_store, store_path, &current, &new)?;
+    eprintln!("Database password changed");
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+use matrix_sdk::encryption::backups::BackupState;
+use matrix_sdk::encryption::recovery::RecoveryError as SdkRecoveryError;
+use matrix_sdk::encryption::secret_storage::SecretStorageError;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use serde::Serialize;
+use std::fmt;
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub enum RecoveryError {
+    /// The recovery key or passphrase doesn't open the secret storage
+    WrongKey,
+    /// The account has no secret storage to unlock
+    NotSetUp,
+    /// Secret storage can't be set up in the current state
+    Unavailable(String),
+}
+
+impl fmt::Display for RecoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryError::WrongKey => write!(f, "Wrong recovery key or passphrase"),
+            RecoveryError::NotSetUp => write!(f, "The account has no secret storage"),
+            RecoveryError::Unavailable(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RecoveryError {}
+
+/// What the account's secret storage (4S) holds for this device
+#[derive(Debug, Clone, Serialize)]
+pub struct SecretStorageStatus {
+    /// The account has a default secret storage key
+    pub enabled: bool,
+    /// This device has all three private cross-signing keys
+    pub cross_signing_complete: bool,
+    /// This device is signed by the account's self-signing key, so other
+    /// sessions trusting the account trust the bot too
+    pub device_verified: bool,
+}
+
+pub async fn secret_storage_status(client: &Client) -> Result<SecretStorageStatus> {
+    let encryption = client.encryption();
+
+    let enabled = encryption.secret_storage().is_enabled().await?;
+    let cross_signing_complete = encryption
+        .cross_signing_status()
+        .await
+        .is_some_and(|status| status.is_complete());
+    let device_verified = encryption
+        .get_own_device()
+        .await?
+        .is_some_and(|device| device.is_cross_signed_by_owner());
+
+    Ok(SecretStorageStatus {
+        enabled,
+        cross_signing_complete,
+        device_verified,
+    })
+}
+
+/// Create the account's secret storage and store this device's private
+/// cross-signing keys (and the backup key, if any) in it. Returns the
+/// recovery key, which is only shown this once.
+pub async fn set_up_secret_storage(client: &Client, passphrase: Option<&str>) -> Result<String> {
+    let encryption = client.encryption();
+
+    if encryption.secret_storage().is_enabled().await? {
+        return Err(RecoveryError::Unavailable(
+            "Secret storage is already set up, unlock it with its recovery key or passphrase".to_string(),
+        )
+        .into());
+    }
+    if !encryption.cross_signing_status().await.is_some_and(|status| status.is_complete()) {
+        return Err(RecoveryError::Unavailable(
+            "This device doesn't have the cross-signing keys to store, verify it from another session first".to_string(),
+        )
+        .into());
+    }
+
+    let secret_storage = encryption.secret_storage();
+    let create = secret_storage.create_secret_store();
+    let store = match passphrase {
+        Some(passphrase) => create.with_passphrase(passphrase).await?,
+        None => create.await?,
+    };
+
+    info!("Secret storage set up with the cross-signing keys of this device");
+    Ok(store.secret_storage_key())
+}
+
+/// Open the account's secret storage and import the private cross-signing
+/// keys and the backup key from it. With the self-signing key, the device
+/// signs itself and becomes trusted without an interactive verification.
+pub async fn unlock_secret_storage(client: &Client, recovery_key: &str) -> Result<()> {
+    let store = match client.encryption().secret_storage().open_secret_store(recovery_key).await {
+        Ok(store) => store,
+        Err(SecretStorageError::SecretStorageKey(_)) => return Err(RecoveryError::WrongKey.into()),
+        Err(SecretStorageError::MissingKeyInfo { .. }) => return Err(RecoveryError::NotSetUp.into()),
+        Err(e) => return Err(e.into()),
+    };
+
+    store.import_secrets().await?;
+    info!("Imported secrets from secret storage");
+    Ok(())
+}
+
+/// State of the server-side room key backup for this device
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupStatus {
+    /// What the backup of this device is doing, e.g. `enabled` or `downloading`
+    pub state: &'static str,
+    /// This device uploads its room keys to the backup and can download
+    /// keys from it
+    pub enabled: bool,
+    /// The account has a backup on the server
+    pub exists_on_server: bool,
+}
+
+pub async fn backup_status(client: &Client) -> Result<BackupStatus> {
+    let backups = client.encryption().backups();
+
+    let state = match backups.state() {
+        BackupState::Unknown => "unknown",
+        BackupState::Creating => "creating",
+        BackupState::Enabling => "enabling",
+        BackupState::Resuming => "resuming",
+        BackupState::Enabled => "enabled",
+        BackupState::Downloading => "downloading",
+        BackupState::Disabling => "disabling",
+    };
+
+    Ok(BackupStatus {
+        state,
+        enabled: backups.are_enabled().await,
+        exists_on_server: backups.exists_on_server().await?,
+    })
+}
+
+/// Create a new key backup on the server and start uploading the room keys
+/// of this device to it.
+///
+/// Without secret storage, the backup key is only kept on this device until
+/// secret storage is set up. When secret storage exists, it is unlocked with
+/// `recovery_key` first, then re-created holding the cross-signing keys and
+/// the new backup key, optionally with a new `passphrase`. Its new recovery
+/// key is returned, and only shown this once.
+pub async fn enable_backup(
+    client: &Client,
+    recovery_key: Option<&str>,
+    passphrase: Option<&str>,
+) -> Result<Option<String>> {
+    let encryption = client.encryption();
+
+    if encryption.backups().are_enabled().await {
+        return Err(RecoveryError::Unavailable("Key backup is already enabled".to_string()).into());
+    }
+
+    let result = if encryption.secret_storage().is_enabled().await? {
+        let recovery_key = recovery_key.ok_or_else(|| {
+            RecoveryError::Unavailable(
+                "Secret storage is set up, enter its recovery key or passphrase to store the backup key in it"
+                    .to_string(),
+            )
+        })?;
+        // Import the secrets first, so the re-created secret storage keeps them
+        unlock_secret_storage(client, recovery_key).await?;
+
+        let recovery = encryption.recovery();
+        let enable = recovery.enable();
+        match passphrase {
+            Some(passphrase) => enable.with_passphrase(passphrase).await.map(Some),
+            None => enable.await.map(Some),
+        }
+    } else {
+        encryption.recovery().enable_backup().await.map(|()| None)
+    };
+
+    match result {
+        Ok(new_recovery_key) => {
+            if new_recovery_key.is_some() {
+                info!("Key backup created and its key stored in new secret storage, uploading room keys");
+            } else {
+                info!("Key backup created, uploading room keys");
+            }
+            Ok(new_recovery_key)
+        }
+        Err(SdkRecoveryError::BackupExistsOnServer) => Err(RecoveryError::Unavailable(
+            "The account already has a key backup, restore it with the recovery key".to_string(),
+        )
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Unlock secret storage to get the backup key, then download the keys of
+/// the given rooms from the backup. Messages are decrypted as the keys are
+/// imported.
+pub async fn restore_backup(client: &Client, recovery_key: &str, room_ids: &[String]) -> Result<()> {
+    unlock_secret_storage(client, recovery_key).await?;
+
+    let backups = client.encryption().backups();
+    if !backups.are_enabled().await {
+        return Err(RecoveryError::Unavailable(
+            "Secret storage has no key for the account's current key backup".to_string(),
+        )
+        .into());
+    }
+
+    for room_id in room_ids {
+        let room_id = <&RoomId>::try_from(room_id.as_str())?;
+        if let Err(e) = backups.download_room_keys_for_room(room_id).await {
+            warn!("Failed to download room keys for {} from the backup: {}", room_id, e);
+        }
+    }
+
+    info!("Restored room keys from the key backup");
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{File, TryLockError};
+use std::path::Path;
+
+/// Name of the lock file inside an account's Matrix store
+const LOCK_FILE: &str = "matrix-web.lock";
+
+/// Exclusive lock on an account's Matrix store, released when dropped.
+///
+/// Two processes using one store would sync the same device, and each would
+/// consume to-device room keys meant for the other, so the web server and
+/// the subcommands take this lock before touching the store.
+pub struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    /// Lock the store at `store_path`, failing if another process holds it
+    pub fn acquire(store_path: &str) -> Result<Self> {
+        std::fs::create_dir_all(store_path).with_context(|| format!("Failed to create the Matrix store {}", store_path))?;
+        let path = Path::new(store_path).join(LOCK_FILE);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => bail!(
+                "The Matrix store {} is in use by another process. Stop the web server before running a subcommand",
+                store_path
+            ),
+            Err(TryLockError::Error(e)) => Err(e).with_context(|| format!("Failed to lock {}", path.display())),
+        }
+    }
+}
+
+/// Whether the lock file is all that is left in a store directory
+pub fn holds_only_lock(store_path: &Path) -> bool {
+    std::fs::read_dir(store_path).is_ok_and(|entries| entries.flatten().all(|entry| entry.file_name() == LOCK_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_lock_at_a_time() {
+        let dir = std::env::temp_dir().join(format!("matrix-web-store-lock-{}", std::process::id()));
+        let store_path = dir.to_str().unwrap();
+
+        let lock = StoreLock::acquire(store_path).unwrap();
+        assert!(StoreLock::acquire(store_path).is_err());
+        drop(lock);
+        assert!(StoreLock::acquire(store_path).is_ok());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
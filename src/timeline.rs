@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use crate::messages::MessageEvent;
+
+/// Prefix of pagination tokens that point into the timeline cache instead of
+/// the homeserver
+const CACHE_TOKEN_PREFIX: &str = "cache:";
+
+/// A page of room history, oldest message first
+#[derive(Debug, Clone)]
+pub struct HistoryPage {
+    pub messages: Vec<MessageEvent>,
+    /// Token for the page before this one, `None` at the start of the room
+    pub next_token: Option<String>,
+}
+
+/// The recent messages of a room, kept up to date by sync so history can be
+/// served without a round-trip to the homeserver.
+///
+/// Messages arrive in batches (a history page or a sync response), and the
+/// homeserver only gives us a pagination token for the start of each batch.
+/// The cache is therefore only ever trimmed at a batch start, so the oldest
+/// cached message always has a token to continue from.
+#[derive(Debug)]
+pub struct RoomTimeline {
+    messages: Vec<MessageEvent>,
+    /// First cached message of each batch, with the token for the events before it
+    batch_starts: HashMap<String, Option<String>>,
+    /// Token for the events before the oldest cached message
+    prev_batch: Option<String>,
+    /// Whether events may be missing between the cached messages and the live timeline
+    has_gap: bool,
+    capacity: usize,
+}
+
+impl RoomTimeline {
+    /// An empty timeline that still has to be filled from the homeserver
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: Vec::new(),
+            batch_starts: HashMap::new(),
+            prev_batch: None,
+            has_gap: true,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Replace the cached messages with the latest page of history. Cached
+    /// messages older than the page are kept if the page starts at a cached
+    /// message, since they still join up with it.
+    pub fn fill(&mut self, page: HistoryPage) {
+        let Some(first) = page.messages.first() else {
+            self.messages.clear();
+            self.batch_starts.clear();
+            self.prev_batch = page.next_token;
+            self.has_gap = false;
+            return;
+        };
+
+        match self.messages.iter().position(|m| m.event_id == first.event_id) {
+            Some(start) => {
+                self.messages.truncate(start);
+                let messages = &self.messages;
+                self.batch_starts.retain(|event_id, _| messages.iter().any(|m| &m.event_id == event_id));
+                if self.messages.is_empty() {
+                    self.prev_batch = page.next_token.clone();
+                }
+            }
+            None => {
+                self.messages.clear();
+                self.batch_starts.clear();
+                self.prev_batch = page.next_token.clone();
+            }
+        }
+
+        self.batch_starts.insert(first.event_id.clone(), page.next_token);
+        self.messages.extend(page.messages);
+        self.has_gap = false;
+        self.trim();
+    }
+
+    /// Whether the cache must be refilled from the homeserver before it can be served
+    pub fn has_gap(&self) -> bool {
+        self.has_gap
+    }
+
+    /// Record that the homeserver skipped events (a limited sync), so the
+    /// cached messages no longer join up with the live timeline
+    pub fn mark_gap(&mut self) {
+        self.has_gap = true;
+    }
+
+    pub fn messages(&self) -> &[MessageEvent] {
+        &self.messages
+    }
+
+    pub fn messages_mut(&mut self) -> &mut [MessageEvent] {
+        &mut self.messages
+    }
+
+    /// Append a new message. Returns `false` if it was already cached.
+    ///
+    /// The cache is only trimmed once the message's batch is recorded with
+    /// [`mark_batch`](Self::mark_batch), since it can't be cut before then.
+    pub fn push(&mut self, message: MessageEvent) -> bool {
+        if self.messages.iter().rev().any(|m| m.event_id == message.event_id) {
+            return false;
+        }
+        self.messages.push(message);
+        true
+    }
+
+    pub fn remove(&mut self, event_id: &str) {
+        self.messages.retain(|m| m.event_id != event_id);
+        self.batch_starts.remove(event_id);
+    }
+
+    /// Record the pagination token of a sync batch, given the IDs of its
+    /// events in timeline order
+    pub fn mark_batch<'a>(&mut self, event_ids: impl IntoIterator<Item = &'a str>, prev_batch: Option<String>) {
+        let first_cached = event_ids
+            .into_iter()
+            .find(|id| self.messages.iter().any(|m| m.event_id == *id));
+        if let Some(event_id) = first_cached {
+            self.batch_starts.insert(event_id.to_string(), prev_batch);
+        }
+        self.trim();
+    }
+
+    /// Up to `limit` cached messages before the message `before`, or the
+    /// latest ones when `before` is `None`.
+    ///
+    /// Returns `None` if `before` is no longer cached.
+    pub fn page(&self, before: Option<&str>, limit: usize) -> Option<HistoryPage> {
+        let end = match before {
+            Some(event_id) => self.messages.iter().position(|m| m.event_id == event_id)?,
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(limit);
+
+        // Continue from the cache while it has older messages, then from the homeserver
+        let next_token = if start > 0 {
+            Some(format!("{}{}", CACHE_TOKEN_PREFIX, self.messages[start].event_id))
+        } else {
+            self.prev_batch.clone()
+        };
+
+        Some(HistoryPage {
+            messages: self.messages[start..end].to_vec(),
+            next_token,
+        })
+    }
+
+    /// Drop the oldest messages beyond the capacity, cutting at a batch start.
+    ///
+    /// If no batch starts within the newest `capacity` messages, the cache is
+    /// left over capacity until a later batch gives it somewhere to cut.
+    fn trim(&mut self) {
+        if self.messages.len() <= self.capacity {
+            return;
+        }
+
+        let Some(cut) = (self.messages.len() - self.capacity..self.messages.len())
+            .find(|&i| self.batch_starts.contains_key(&self.messages[i].event_id))
+        else {
+            return;
+        };
+
+        self.messages.drain(..cut);
+        self.prev_batch = self.batch_starts.get(&self.messages[0].event_id).cloned().flatten();
+
+        let messages = &self.messages;
+        self.batch_starts.retain(|event_id, _| messages.iter().any(|m| &m.event_id == event_id));
+    }
+}
+
+/// The cached message a pagination token points before, if it is a cache token
+pub fn cache_token_event(token: &str) -> Option<&str> {
+    token.strip_prefix(CACHE_TOKEN_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(event_id: &str) -> MessageEvent {
+        MessageEvent::text(event_id, "!room:example.org", "@alice:example.org", event_id)
+    }
+
+    fn page(event_ids: &[&str], next_token: &str) -> HistoryPage {
+        HistoryPage {
+            messages: event_ids.iter().map(|id| message(id)).collect(),
+            next_token: Some(next_token.to_string()),
+        }
+    }
+
+    fn event_ids(timeline: &RoomTimeline) -> Vec<&str> {
+        timeline.messages().iter().map(|m| m.event_id.as_str()).collect()
+    }
+
+    /// Push a sync batch the way the event handlers and sync callback do
+    fn sync(timeline: &mut RoomTimeline, event_ids: &[&str], prev_batch: &str) {
+        for event_id in event_ids {
+            timeline.push(message(event_id));
+        }
+        timeline.mark_batch(event_ids.iter().copied(), Some(prev_batch.to_string()));
+    }
+
+    #[test]
+    fn full_cache_keeps_messages_on_the_next_sync() {
+        let mut timeline = RoomTimeline::new(3);
+        timeline.fill(page(&["a", "b", "c"], "t0"));
+
+        sync(&mut timeline, &["d"], "t1");
+
+        // The new batch is the only place to cut, and its message survives the trim
+        assert_eq!(event_ids(&timeline), ["d"]);
+        assert!(!timeline.has_gap());
+        assert_eq!(timeline.page(None, 10).unwrap().next_token.as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn trims_at_the_oldest_batch_start_within_capacity() {
+        let mut timeline = RoomTimeline::new(4);
+        timeline.fill(page(&["a", "b"], "t0"));
+        sync(&mut timeline, &["c", "d"], "t1");
+        sync(&mut timeline, &["e"], "t2");
+
+        assert_eq!(event_ids(&timeline), ["c", "d", "e"]);
+        assert!(!timeline.has_gap());
+        assert_eq!(timeline.page(Some("c"), 10).unwrap().next_token.as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn stays_over_capacity_until_there_is_a_batch_start_to_cut_at() {
+        let mut timeline = RoomTimeline::new(2);
+        timeline.fill(page(&["a", "b", "c"], "t0"));
+        assert_eq!(event_ids(&timeline), ["a", "b", "c"]);
+
+        timeline.push(message("d"));
+        assert_eq!(event_ids(&timeline), ["a", "b", "c", "d"]);
+
+        timeline.mark_batch(["d"], Some("t1".to_string()));
+        assert_eq!(event_ids(&timeline), ["d"]);
+    }
+
+    #[test]
+    fn push_skips_cached_messages() {
+        let mut timeline = RoomTimeline::new(5);
+        timeline.fill(page(&["a", "b"], "t0"));
+
+        assert!(!timeline.push(message("b")));
+        assert!(timeline.push(message("c")));
+        assert_eq!(event_ids(&timeline), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn fill_keeps_older_messages_that_join_up_with_the_page() {
+        let mut timeline = RoomTimeline::new(10);
+        timeline.fill(page(&["a", "b"], "t0"));
+        sync(&mut timeline, &["c"], "t1");
+
+        timeline.fill(page(&["b", "c", "d"], "t2"));
+
+        assert_eq!(event_ids(&timeline), ["a", "b", "c", "d"]);
+        assert_eq!(timeline.page(Some("b"), 10).unwrap().next_token.as_deref(), Some("t0"));
+    }
+
+    #[test]
+    fn fill_replaces_messages_that_do_not_join_up() {
+        let mut timeline = RoomTimeline::new(10);
+        timeline.fill(page(&["a", "b"], "t0"));
+        timeline.mark_gap();
+
+        timeline.fill(page(&["x", "y"], "t1"));
+
+        assert_eq!(event_ids(&timeline), ["x", "y"]);
+        assert!(!timeline.has_gap());
+        assert_eq!(timeline.page(None, 10).unwrap().next_token.as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn pages_continue_from_the_cache_then_the_homeserver() {
+        let mut timeline = RoomTimeline::new(10);
+        timeline.fill(page(&["a", "b", "c", "d"], "t0"));
+
+        let latest = timeline.page(None, 2).unwrap();
+        assert_eq!(latest.messages.iter().map(|m| m.event_id.as_str()).collect::<Vec<_>>(), ["c", "d"]);
+        let token = latest.next_token.unwrap();
+        assert_eq!(cache_token_event(&token), Some("c"));
+
+        let older = timeline.page(cache_token_event(&token), 5).unwrap();
+        assert_eq!(older.messages.iter().map(|m| m.event_id.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(older.next_token.as_deref(), Some("t0"));
+
+        assert!(timeline.page(Some("gone"), 5).is_none());
+        assert_eq!(cache_token_event("t0"), None);
+    }
+}
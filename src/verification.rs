@@ -0,0 +1,141 @@
+use anyhow::Result;
+use matrix_sdk::encryption::verification::{
+    QrVerification, QrVerificationData, QrVerificationState, Verification, VerificationRequest,
+};
+use matrix_sdk::ruma::events::key::verification::VerificationMethod;
+use matrix_sdk::ruma::{DeviceId, UserId};
+use matrix_sdk::Client;
+use std::fmt;
+use tracing::info;
+
+#[derive(Debug)]
+pub enum VerificationError {
+    /// The bot doesn't know the keys of the user or device
+    NotFound(String),
+    /// The verification can't be requested as asked
+    Invalid(String),
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerificationError::NotFound(msg) => write!(f, "{}", msg),
+            VerificationError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+/// Methods the bot offers: emoji, and QR codes it shows or that are
+/// uploaded to it, e.g. photographed with a phone
+pub const SUPPORTED_METHODS: [VerificationMethod; 4] = [
+    VerificationMethod::SasV1,
+    VerificationMethod::QrCodeShowV1,
+    VerificationMethod::QrCodeScanV1,
+    VerificationMethod::ReciprocateV1,
+];
+
+/// Ask a user, or one of their devices, to verify the bot.
+///
+/// A device gets the request as a to-device message. Without a device,
+/// other users get an `m.key.verification.request` message in their direct
+/// room with the bot, which is created if needed, and the bot's own account
+/// gets the request on all of its other devices.
+pub async fn request_verification(client: &Client, user_id: &str, device_id: Option<&str>) -> Result<VerificationRequest> {
+    let user_id = <&UserId>::try_from(user_id)
+        .map_err(|_| VerificationError::Invalid(format!("Invalid user ID: {}", user_id)))?;
+    let encryption = client.encryption();
+
+    if let Some(device_id) = device_id {
+        let device_id = <&DeviceId>::from(device_id);
+        if client.user_id() == Some(user_id) && client.device_id() == Some(device_id) {
+            return Err(VerificationError::Invalid("The bot can't verify its own device".to_string()).into());
+        }
+
+        let device = encryption.get_device(user_id, device_id).await?.ok_or_else(|| {
+            VerificationError::NotFound(format!("Unknown device {} of {}", device_id, user_id))
+        })?;
+        info!("Requesting verification of device {} of {}", device_id, user_id);
+        return Ok(device.request_verification_with_methods(SUPPORTED_METHODS.to_vec()).await?);
+    }
+
+    // Identities are only known for users the bot shares an encrypted room with
+    let identity = encryption.get_user_identity(user_id).await?.ok_or_else(|| {
+        VerificationError::NotFound(format!("{} has no cross-signing keys the bot knows of", user_id))
+    })?;
+    info!("Requesting verification of {}", user_id);
+    Ok(identity.request_verification_with_methods(SUPPORTED_METHODS.to_vec()).await?)
+}
+
+/// The QR code the bot shows for a request, created on first use. Showing
+/// it again returns the same code, so a reload doesn't invalidate a code the
+/// other side is about to scan.
+pub async fn qr_code(client: &Client, user_id: &UserId, flow_id: &str) -> Result<QrVerification> {
+    let encryption = client.encryption();
+
+    match encryption.get_verification(user_id, flow_id).await {
+        Some(Verification::QrV1(qr)) if matches!(qr.state(), QrVerificationState::Started) => return Ok(qr),
+        Some(Verification::SasV1(_)) => {
+            return Err(VerificationError::Invalid("Emoji verification has already started".to_string()).into());
+        }
+        _ => {}
+    }
+
+    let request = encryption.get_verification_request(user_id, flow_id).await.ok_or_else(|| {
+        VerificationError::NotFound(format!("Unknown verification request {}", flow_id))
+    })?;
+    request.generate_qr_code().await?.ok_or_else(|| {
+        VerificationError::Invalid(
+            "No QR code for this request: it isn't accepted yet, or the other side can't scan codes".to_string(),
+        )
+        .into()
+    })
+}
+
+/// Render a QR code as an SVG image
+pub fn qr_code_svg(qr: &QrVerification) -> Result<String> {
+    let code = qr.to_qr_code()?;
+    Ok(code
+        .render::<qrcode::render::svg::Color<'_>>()
+        .min_dimensions(256, 256)
+        .build())
+}
+
+/// Find the Matrix verification QR code in a PNG or JPEG image, such as a
+/// screenshot or a photo of the other device's screen
+pub fn decode_qr_image(image: &[u8]) -> Result<QrVerificationData, VerificationError> {
+    let image = image::load_from_memory(image)
+        .map_err(|e| VerificationError::Invalid(format!("Unreadable image: {}", e)))?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+
+    for grid in prepared.detect_grids() {
+        // The code holds binary data, which rqrr's string decoding would mangle
+        let mut bytes = Vec::new();
+        if grid.decode_to(&mut bytes).is_err() {
+            continue;
+        }
+        if let Ok(data) = QrVerificationData::from_bytes(&bytes) {
+            return Ok(data);
+        }
+    }
+
+    Err(VerificationError::Invalid("No Matrix verification QR code found in the image".to_string()))
+}
+
+/// Verify the other device with the QR code it shows. The other side still
+/// has to confirm that the bot scanned it.
+pub async fn scan_qr_code(client: &Client, user_id: &UserId, flow_id: &str, data: QrVerificationData) -> Result<QrVerification> {
+    if data.flow_id() != flow_id {
+        return Err(VerificationError::Invalid("The QR code belongs to another verification".to_string()).into());
+    }
+
+    let request = client.encryption().get_verification_request(user_id, flow_id).await.ok_or_else(|| {
+        VerificationError::NotFound(format!("Unknown verification request {}", flow_id))
+    })?;
+    info!("Scanned the QR code of {} for verification {}", user_id, flow_id);
+    request.scan_qr_code(data).await?.ok_or_else(|| {
+        VerificationError::Invalid("The verification request isn't accepted yet".to_string()).into()
+    })
+}
@@ -1,32 +1,106 @@
 use axum::{
-    extract::State,
-    http::{Request, StatusCode},
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{header, Request, StatusCode},
     middleware::{self, Next},
-    response::{sse::{Event, KeepAlive}, Html, IntoResponse, Response, Sse},
-    routing::{get, post},
+    response::{sse::{Event, KeepAlive}, Html, IntoResponse, Redirect, Response, Sse},
+    routing::{get, post, put},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
 use std::sync::Arc;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::StreamExt;
 use tracing::{info, warn};
 
-use crate::bot::{MatrixBot, VerificationRequestInfo, SasInfo};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use matrix_sdk::ruma::api::client::session::get_login_types::v3::LoginType;
+use subtle::ConstantTimeEq;
+
+use crate::archive::MessageArchive;
+use crate::bot::{Login, MatrixBot, RoomInfo, VerificationRequestInfo, SasInfo};
 use crate::config::{AuthConfig, hash_value};
 use crate::credentials::CredentialStore;
+use crate::export::{export_file_name, ChunkWriter, DateRange, ExportFormat, RoomExport};
+use crate::media::{AttachmentDimensions, MediaError};
+use crate::messages::{summarize_threads, MessageError, MessageEvent, MessageOptions, RoomUpdate, ThreadSummary};
+use crate::passphrase::{rotate_passphrase, PassphraseError};
+use crate::recovery::{BackupStatus, RecoveryError, SecretStorageStatus};
+use crate::verification::VerificationError;
+
+/// Extra request body allowance on top of the attachment size limit
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+/// How long a full logout can be confirmed after it was requested
+const LOGOUT_CONFIRMATION_TTL: Duration = Duration::from_secs(120);
+/// How long the homeserver has to redirect back after an SSO login starts
+const SSO_LOGIN_TTL: Duration = Duration::from_secs(600);
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 200;
 
+/// State of one bot account's API
 #[derive(Clone)]
 pub struct AppState {
+    /// Account ID from the config, used in the account's API paths
+    pub account_id: String,
     pub bot: MatrixBot,
-    pub auth: Option<AuthConfig>,
     pub credentials_store: CredentialStore,
+    /// Message archive, `None` when disabled in the config
+    pub archive: Option<MessageArchive>,
     pub username: String,
+    /// Full logout waiting for its confirmation step
+    pub pending_logout: Arc<Mutex<Option<PendingLogout>>>,
+    /// SSO login waiting for the homeserver to redirect back
+    pub pending_sso: Arc<Mutex<Option<PendingSso>>>,
+}
+
+/// A requested full logout, carried out once its token is sent back
+pub struct PendingLogout {
+    token: String,
+    forget_credentials: bool,
+    expires_at: Instant,
+}
+
+/// A started SSO login, completed by the callback carrying its state
+pub struct PendingSso {
+    state: String,
+    sqlite_password: String,
+    expires_at: Instant,
 }
 
 #[derive(Deserialize)]
 pub struct SendMessageRequest {
     pub message: String,
+    /// Render the message as Markdown (default: true)
+    #[serde(default = "default_markdown")]
+    pub markdown: bool,
+    /// Event to reply to, adding a rich reply fallback
+    #[serde(default)]
+    pub reply_to_event_id: Option<String>,
+    /// Root event of the thread to post in
+    #[serde(default)]
+    pub thread_root_event_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EditMessageRequest {
+    pub message: String,
+    /// Render the message as Markdown (default: true)
+    #[serde(default = "default_markdown")]
+    pub markdown: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RedactMessageQuery {
+    pub reason: Option<String>,
+}
+
+fn default_markdown() -> bool {
+    true
 }
 
 #[derive(Serialize)]
@@ -37,7 +111,52 @@ pub struct SendMessageResponse {
 
 #[derive(Serialize)]
 pub struct MessageHistoryResponse {
-    pub messages: Vec<String>,
+    pub messages: Vec<MessageEvent>,
+    /// Threads with replies in `messages`
+    pub threads: Vec<ThreadSummary>,
+    /// Pass as `from` to get the page before this one; `None` at the start of the room
+    pub next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// Pagination token from a previous page
+    pub from: Option<String>,
+    /// Number of events to fetch (default: `message_history.limit`)
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    /// Only search this room
+    pub room_id: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    /// Matching messages, best match first
+    pub results: Vec<MessageEvent>,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// `jsonl` (default), `html` or `txt`
+    pub format: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MediaQuery {
+    #[serde(default)]
+    pub thumbnail: bool,
+}
+
+#[derive(Serialize)]
+pub struct RoomsResponse {
+    pub rooms: Vec<RoomInfo>,
 }
 
 #[derive(Deserialize)]
@@ -46,16 +165,144 @@ pub struct LoginRequest {
     pub sqlite_password: String,
 }
 
+#[derive(Deserialize)]
+pub struct AccessTokenLoginRequest {
+    pub access_token: String,
+    pub device_id: String,
+    pub sqlite_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct SsoLoginRequest {
+    pub sqlite_password: String,
+    /// Origin the browser reaches this server at, for the callback URL
+    pub redirect_base: String,
+    /// Identity provider to go to directly, instead of the homeserver's picker
+    pub idp_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SsoLoginResponse {
+    pub success: bool,
+    pub redirect_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct SsoCallbackQuery {
+    pub state: String,
+    #[serde(rename = "loginToken")]
+    pub login_token: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginFlowsResponse {
+    pub password: bool,
+    pub sso: bool,
+    pub identity_providers: Vec<IdentityProviderInfo>,
+}
+
+#[derive(Serialize)]
+pub struct IdentityProviderInfo {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassphraseRequest {
+    pub current_passphrase: String,
+    pub new_passphrase: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetUpSecretStorageRequest {
+    /// Passphrase that opens the secret storage besides the recovery key
+    pub passphrase: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SetUpSecretStorageResponse {
+    pub success: bool,
+    /// Shown only once; needed to unlock the secret storage on new deployments
+    pub recovery_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockSecretStorageRequest {
+    /// Recovery key or passphrase of the secret storage
+    pub recovery_key: String,
+}
+
+#[derive(Serialize)]
+pub struct SecretStorageResponse {
+    pub success: bool,
+    pub status: SecretStorageStatus,
+}
+
+#[derive(Serialize)]
+pub struct BackupResponse {
+    pub success: bool,
+    pub status: BackupStatus,
+    /// New recovery key when secret storage was re-created to hold the
+    /// backup key; shown only once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_key: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct EnableBackupRequest {
+    /// Recovery key or passphrase of the existing secret storage
+    pub recovery_key: Option<String>,
+    /// Passphrase for the re-created secret storage
+    pub passphrase: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutQuery {
+    /// Log the device out on the server and delete the local session and store
+    #[serde(default)]
+    pub full: bool,
+    /// With `full`, also delete the stored credentials
+    #[serde(default)]
+    pub forget_credentials: bool,
+    /// Token from the first step of a full logout
+    pub confirm: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LogoutConfirmationResponse {
+    pub success: bool,
+    pub confirmation_required: bool,
+    pub confirm_token: String,
+    pub expires_in_secs: u64,
+    /// What confirming will do
+    pub actions: Vec<String>,
+}
+
 #[derive(Serialize)]
 pub struct LoginResponse {
     pub success: bool,
     pub error: Option<String>,
 }
 
+#[derive(Serialize)]
+pub struct AccountInfo {
+    pub id: String,
+    pub username: String,
+    pub connected: bool,
+}
+
+#[derive(Serialize)]
+pub struct AccountsResponse {
+    /// In config order; the first one also serves the unprefixed API
+    pub accounts: Vec<AccountInfo>,
+}
+
 #[derive(Serialize)]
 pub struct StatusResponse {
     pub connected: bool,
     pub credentials_exist: bool,
+    /// The bot's Matrix user ID, once connected
+    pub user_id: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -70,6 +317,19 @@ pub struct VerificationResponse {
     pub error: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct StartVerificationRequest {
+    pub user_id: String,
+    /// Verify this device only, instead of the user's cross-signing identity
+    pub device_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StartVerificationResponse {
+    pub success: bool,
+    pub request: VerificationRequestInfo,
+}
+
 #[derive(Serialize)]
 pub struct VerificationRequestsResponse {
     pub requests: Vec<VerificationRequestInfo>,
@@ -81,63 +341,128 @@ pub struct SasStatusResponse {
     pub sas_info: Option<SasInfo>,
 }
 
-pub fn create_router(state: AppState) -> Router {
-    let router = Router::new()
+pub fn create_router(accounts: Vec<AppState>, auth: Option<AuthConfig>) -> Router {
+    let accounts: Vec<Arc<AppState>> = accounts.into_iter().map(Arc::new).collect();
+
+    let mut router = Router::new()
         .route("/", get(index_handler))
-        .route("/api/login", post(login_handler))
-        .route("/api/logout", post(logout_handler))
-        .route("/api/status", get(status_handler))
-        .route("/api/messages", post(send_message_handler))
-        .route("/api/history", get(get_message_history_handler))
-        .route("/api/stream", get(stream_messages_handler))
-        .route("/api/verification/requests", get(get_verification_requests_handler))
-        .route("/api/verification/accept", post(accept_verification_handler))
-        .route("/api/verification/confirm", post(confirm_verification_handler))
-        .route("/api/verification/cancel", post(cancel_verification_handler))
-        .route("/api/verification/sas", get(get_sas_status_handler));
+        .route("/api/accounts", get(list_accounts_handler))
+        .with_state(Arc::new(accounts.clone()));
+    // The homeserver's redirect can't carry the authentication header
+    let mut sso_callbacks = Router::new();
+
+    for (index, state) in accounts.into_iter().enumerate() {
+        let prefix = format!("/api/accounts/{}", state.account_id);
+        let api = account_router(state.clone());
+        // The first account also serves the unprefixed API
+        if index == 0 {
+            router = router.nest("/api", api.clone());
+        }
+        router = router.nest(&prefix, api);
+        sso_callbacks = sso_callbacks.route(
+            &format!("{}/login/sso/callback", prefix),
+            get(sso_callback_handler).with_state(state),
+        );
+    }
 
     // Apply authentication middleware if configured
-    if state.auth.is_some() {
-        router
-            .layer(middleware::from_fn_with_state(
-                Arc::new(state.clone()),
-                auth_middleware,
-            ))
-            .with_state(Arc::new(state))
-    } else {
-        router.with_state(Arc::new(state))
+    if let Some(auth) = auth {
+        router = router.layer(middleware::from_fn_with_state(Arc::new(auth), auth_middleware));
     }
+    router.merge(sso_callbacks)
+}
+
+/// The API of one account, mounted under its prefix
+fn account_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/login", post(login_handler))
+        .route("/login/flows", get(login_flows_handler))
+        .route("/login/token", post(access_token_login_handler))
+        .route("/login/sso", post(start_sso_login_handler))
+        .route("/logout", post(logout_handler))
+        .route("/status", get(status_handler))
+        .route("/passphrase", post(change_passphrase_handler))
+        .route("/recovery", get(secret_storage_status_handler))
+        .route("/recovery/setup", post(set_up_secret_storage_handler))
+        .route("/recovery/unlock", post(unlock_secret_storage_handler))
+        .route("/backup", get(backup_status_handler))
+        .route("/backup/enable", post(enable_backup_handler))
+        .route("/backup/restore", post(restore_backup_handler))
+        .route("/messages", post(send_message_handler))
+        .route("/messages/:event_id", put(edit_message_handler).delete(redact_message_handler))
+        .route(
+            "/messages/:event_id/reactions/:key",
+            put(add_reaction_handler).delete(remove_reaction_handler),
+        )
+        .route("/history", get(get_message_history_handler))
+        .route("/stream", get(stream_messages_handler))
+        .route("/rooms", get(list_rooms_handler))
+        .route("/rooms/:room_id/messages", get(get_room_history_handler).post(send_room_message_handler))
+        .route("/rooms/:room_id/stream", get(stream_room_messages_handler))
+        .route("/rooms/:room_id/export", get(export_room_handler))
+        .route("/search", get(search_handler))
+        .route("/media/:event_id", get(get_media_handler))
+        .route(
+            "/rooms/:room_id/attachments",
+            // Leave room for the multipart framing and the dimension fields
+            post(upload_attachment_handler)
+                .layer(DefaultBodyLimit::max(state.bot.media_max_size() as usize + MULTIPART_OVERHEAD)),
+        )
+        .route("/verification/requests", get(get_verification_requests_handler))
+        .route("/verification/request", post(start_verification_handler))
+        .route("/verification/accept", post(accept_verification_handler))
+        .route("/verification/confirm", post(confirm_verification_handler))
+        .route("/verification/cancel", post(cancel_verification_handler))
+        .route("/verification/sas", get(get_sas_status_handler))
+        .route("/verification/qr", get(verification_qr_code_handler))
+        .route(
+            "/verification/qr/scan",
+            // Photos of a screen easily exceed the default limit
+            post(scan_verification_qr_code_handler)
+                .layer(DefaultBodyLimit::max(state.bot.media_max_size() as usize + MULTIPART_OVERHEAD)),
+        )
+        .with_state(state)
 }
 
 async fn auth_middleware(
-    State(state): State<Arc<AppState>>,
+    State(auth_config): State<Arc<AuthConfig>>,
     request: Request<axum::body::Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    if let Some(ref auth_config) = state.auth {
-        let headers = request.headers();
-        
-        if let Some(header_value) = headers.get(&auth_config.header_name) {
-            if let Ok(value_str) = header_value.to_str() {
-                // Hash the incoming header value and compare with stored hash
-                let incoming_hash = hash_value(value_str);
-                if incoming_hash == auth_config.header_value_hash {
-                    return Ok(next.run(request).await);
-                }
+    let headers = request.headers();
+    
+    if let Some(header_value) = headers.get(&auth_config.header_name) {
+        if let Ok(value_str) = header_value.to_str() {
+            // Hash the incoming header value and compare with stored hash
+            let incoming_hash = hash_value(value_str);
+            if incoming_hash == auth_config.header_value_hash {
+                return Ok(next.run(request).await);
             }
         }
-        
-        warn!("Authentication failed: invalid or missing header");
-        return Err(StatusCode::UNAUTHORIZED);
     }
     
-    Ok(next.run(request).await)
+    warn!("Authentication failed: invalid or missing header");
+    Err(StatusCode::UNAUTHORIZED)
 }
 
 async fn index_handler() -> Html<&'static str> {
     Html(include_str!("../static/index.html"))
 }
 
+async fn list_accounts_handler(
+    State(accounts): State<Arc<Vec<Arc<AppState>>>>,
+) -> Json<AccountsResponse> {
+    let mut infos = Vec::with_capacity(accounts.len());
+    for state in accounts.iter() {
+        infos.push(AccountInfo {
+            id: state.account_id.clone(),
+            username: state.username.clone(),
+            connected: state.bot.is_connected().await,
+        });
+    }
+    Json(AccountsResponse { accounts: infos })
+}
+
 async fn status_handler(
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
@@ -149,7 +474,8 @@ async fn status_handler(
             false
         }
     };
-    Json(StatusResponse { connected, credentials_exist })
+    let user_id = state.bot.user_id().await;
+    Json(StatusResponse { connected, credentials_exist, user_id })
 }
 
 async fn login_handler(
@@ -183,7 +509,7 @@ async fn login_handler(
     
     let matrix_password = if credentials_exist {
         // Retrieve stored credentials
-        match state.credentials_store.get_credentials(&payload.sqlite_password) {
+        match state.credentials_store.get_credentials(&payload.sqlite_password).await {
             Ok((stored_username, stored_password)) => {
                 // Verify username matches
                 if stored_username != state.username {
@@ -214,12 +540,12 @@ async fn login_handler(
                 // Store credentials for future use
                 if let Err(e) = state.credentials_store.store_credentials(
                     &state.username,
-                    &password,
+                    Some(&password),
                     &payload.sqlite_password,
-                ) {
+                ).await {
                     warn!("Failed to store credentials: {}", e);
                 }
-                password
+                Some(password)
             }
             None => {
                 return (
@@ -233,7 +559,7 @@ async fn login_handler(
         }
     };
 
-    match state.bot.connect(&matrix_password, &payload.sqlite_password, &state.credentials_store).await {
+    match state.bot.connect(Login::Password(matrix_password.as_deref()), &payload.sqlite_password, &state.credentials_store).await {
         Ok(_) => {
             info!("Bot connected successfully");
             (
@@ -257,9 +583,207 @@ async fn login_handler(
     }
 }
 
+async fn login_flows_handler(
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match state.bot.login_flows().await {
+        Ok(flows) => {
+            let mut response = LoginFlowsResponse {
+                password: false,
+                sso: false,
+                identity_providers: Vec::new(),
+            };
+            for flow in flows {
+                match flow {
+                    LoginType::Password(_) => response.password = true,
+                    LoginType::Sso(sso) => {
+                        response.sso = true;
+                        response.identity_providers.extend(sso.identity_providers.into_iter().map(|idp| {
+                            IdentityProviderInfo {
+                                id: idp.id,
+                                name: idp.name,
+                            }
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+            Json(response).into_response()
+        }
+        Err(e) => {
+            warn!("Failed to get login flows: {}", e);
+            send_error(StatusCode::BAD_GATEWAY, format!("Failed to get login flows: {}", e))
+        }
+    }
+}
+
+/// Check the database password before a login that doesn't use the stored
+/// Matrix password, and create the credentials row if there is none yet
+async fn prepare_external_login(state: &AppState, sqlite_password: &str) -> Result<(), Response> {
+    if state.bot.is_connected().await {
+        return Err(send_error(StatusCode::CONFLICT, "Already connected".to_string()));
+    }
+
+    let credentials_exist = state
+        .credentials_store
+        .credentials_exist()
+        .map_err(|e| send_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
+
+    if credentials_exist {
+        let (stored_username, _) = state.credentials_store.get_credentials(sqlite_password).await.map_err(|e| {
+            send_error(
+                StatusCode::UNAUTHORIZED,
+                format!("Failed to retrieve credentials: {}. Wrong SQLite password?", e),
+            )
+        })?;
+        if stored_username != state.username {
+            return Err(send_error(
+                StatusCode::UNAUTHORIZED,
+                "Username mismatch with stored credentials".to_string(),
+            ));
+        }
+    } else if let Err(e) = state.credentials_store.store_credentials(&state.username, None, sqlite_password).await {
+        warn!("Failed to store credentials: {}", e);
+    }
+
+    Ok(())
+}
+
+async fn access_token_login_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<AccessTokenLoginRequest>,
+) -> Response {
+    if payload.access_token.is_empty() || payload.device_id.is_empty() {
+        return send_error(StatusCode::BAD_REQUEST, "Access token and device ID are required".to_string());
+    }
+    if let Err(response) = prepare_external_login(&state, &payload.sqlite_password).await {
+        return response;
+    }
+
+    let login = Login::AccessToken {
+        access_token: &payload.access_token,
+        device_id: &payload.device_id,
+    };
+    match state.bot.connect(login, &payload.sqlite_password, &state.credentials_store).await {
+        Ok(_) => {
+            info!("Bot connected with imported access token");
+            Json(LoginResponse {
+                success: true,
+                error: None,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            warn!("Access token login failed: {:#}", e);
+            send_error(StatusCode::UNAUTHORIZED, format!("Failed to connect: {:#}", e))
+        }
+    }
+}
+
+async fn start_sso_login_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SsoLoginRequest>,
+) -> Response {
+    if let Err(response) = prepare_external_login(&state, &payload.sqlite_password).await {
+        return response;
+    }
+
+    let mut state_bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut state_bytes);
+    let sso_state = hex::encode(state_bytes);
+
+    let callback_url = match url::Url::parse(&payload.redirect_base)
+        .and_then(|base| base.join(&format!("/api/accounts/{}/login/sso/callback", state.account_id)))
+    {
+        Ok(mut url) => {
+            url.query_pairs_mut().append_pair("state", &sso_state);
+            url
+        }
+        Err(e) => return send_error(StatusCode::BAD_REQUEST, format!("Invalid redirect base: {}", e)),
+    };
+
+    let redirect_url = match state.bot.sso_login_url(callback_url.as_str(), payload.idp_id.as_deref()).await {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Failed to start SSO login: {}", e);
+            return send_error(StatusCode::BAD_GATEWAY, format!("Failed to start SSO login: {}", e));
+        }
+    };
+
+    // Starting another login replaces the previous one
+    *state.pending_sso.lock().await = Some(PendingSso {
+        state: sso_state,
+        sqlite_password: payload.sqlite_password,
+        expires_at: Instant::now() + SSO_LOGIN_TTL,
+    });
+
+    Json(SsoLoginResponse {
+        success: true,
+        redirect_url,
+    })
+    .into_response()
+}
+
+/// Where the homeserver sends the browser back to after SSO. It isn't
+/// behind the header authentication: the one-time state stands in for it.
+async fn sso_callback_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SsoCallbackQuery>,
+) -> Redirect {
+    // A state can only be used once. A wrong one leaves the pending login
+    // alone, so a stray request to this route can't cancel it.
+    let pending = {
+        let mut pending_sso = state.pending_sso.lock().await;
+        if pending_sso.as_ref().is_some_and(|p| p.expires_at <= Instant::now()) {
+            *pending_sso = None;
+        }
+        if pending_sso.as_ref().is_some_and(|p| tokens_match(&p.state, &query.state)) {
+            pending_sso.take()
+        } else {
+            None
+        }
+    };
+    let Some(pending) = pending else {
+        warn!("SSO callback with an unknown or expired state");
+        return account_redirect(&state.account_id, Some("SSO login is invalid or has expired, start it again"));
+    };
+
+    let login = Login::SsoToken(&query.login_token);
+    match state.bot.connect(login, &pending.sqlite_password, &state.credentials_store).await {
+        Ok(_) => {
+            info!("Bot connected with SSO login");
+            account_redirect(&state.account_id, None)
+        }
+        Err(e) => {
+            warn!("SSO login failed: {:#}", e);
+            account_redirect(&state.account_id, Some(&format!("Failed to connect: {:#}", e)))
+        }
+    }
+}
+
+/// Compare a secret token in constant time
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.as_bytes().ct_eq(given.as_bytes()).into()
+}
+
+/// Back to the web UI, showing the account the login was for
+fn account_redirect(account_id: &str, login_error: Option<&str>) -> Redirect {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query.append_pair("account", account_id);
+    if let Some(error) = login_error {
+        query.append_pair("login_error", error);
+    }
+    Redirect::to(&format!("/?{}", query.finish()))
+}
+
 async fn logout_handler(
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
+    Query(query): Query<LogoutQuery>,
+) -> Response {
+    if query.full {
+        return full_logout(&state, query).await;
+    }
+
     match state.bot.disconnect(&state.credentials_store).await {
         Ok(_) => {
             info!("Bot disconnected successfully");
@@ -270,6 +794,7 @@ async fn logout_handler(
                     error: None,
                 }),
             )
+                .into_response()
         }
         Err(e) => {
             warn!("Logout failed: {}", e);
@@ -280,21 +805,339 @@ async fn logout_handler(
                     error: Some(format!("Failed to disconnect: {}", e)),
                 }),
             )
+                .into_response()
         }
     }
 }
 
-async fn get_message_history_handler(
+/// Full logout in two steps: the first request returns a token describing
+/// what will be deleted, and only a second request with that token acts
+async fn full_logout(state: &AppState, query: LogoutQuery) -> Response {
+    let Some(confirm) = query.confirm else {
+        if !state.bot.is_connected().await {
+            return send_error(StatusCode::CONFLICT, "Connect before logging out this device".to_string());
+        }
+
+        let mut token_bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut token_bytes);
+        let token = hex::encode(token_bytes);
+
+        let mut actions = vec![
+            "Log this device out on the homeserver".to_string(),
+            "Delete the stored session".to_string(),
+            format!("Delete the Matrix store databases in {}, including this device's encryption keys", state.bot.store_path()),
+        ];
+        if query.forget_credentials {
+            actions.push("Delete the stored Matrix credentials".to_string());
+        }
+
+        *state.pending_logout.lock().await = Some(PendingLogout {
+            token: token.clone(),
+            forget_credentials: query.forget_credentials,
+            expires_at: Instant::now() + LOGOUT_CONFIRMATION_TTL,
+        });
+
+        return Json(LogoutConfirmationResponse {
+            success: false,
+            confirmation_required: true,
+            confirm_token: token,
+            expires_in_secs: LOGOUT_CONFIRMATION_TTL.as_secs(),
+            actions,
+        })
+        .into_response();
+    };
+
+    // A token can only be used once
+    let pending = state.pending_logout.lock().await.take();
+    let Some(pending) = pending.filter(|p| tokens_match(&p.token, &confirm) && p.expires_at > Instant::now()) else {
+        return send_error(
+            StatusCode::BAD_REQUEST,
+            "Logout confirmation is invalid or has expired, request a new one".to_string(),
+        );
+    };
+
+    match state.bot.logout(&state.credentials_store, pending.forget_credentials).await {
+        Ok(()) => {
+            info!("Device logged out");
+            Json(LoginResponse {
+                success: true,
+                error: None,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            warn!("Full logout failed: {:#}", e);
+            send_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+async fn secret_storage_status_handler(
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Not connected".to_string());
+    }
+
+    match state.bot.secret_storage_status().await {
+        Ok(status) => Json(SecretStorageResponse { success: true, status }).into_response(),
+        Err(e) => {
+            warn!("Failed to get secret storage status: {:#}", e);
+            send_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+async fn set_up_secret_storage_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SetUpSecretStorageRequest>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Connect before setting up secret storage".to_string());
+    }
+
+    let passphrase = payload.passphrase.as_deref().filter(|p| !p.is_empty());
+    match state.bot.set_up_secret_storage(passphrase).await {
+        Ok(recovery_key) => {
+            info!("Secret storage set up");
+            Json(SetUpSecretStorageResponse {
+                success: true,
+                recovery_key,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<RecoveryError>() {
+                Some(RecoveryError::Unavailable(_)) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("Failed to set up secret storage: {:#}", e);
+            send_error(status, format!("{:#}", e))
+        }
+    }
+}
+
+async fn unlock_secret_storage_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<UnlockSecretStorageRequest>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Connect before unlocking secret storage".to_string());
+    }
+
+    match state.bot.unlock_secret_storage(payload.recovery_key.trim()).await {
+        Ok(status) => {
+            info!("Secret storage unlocked, device verified: {}", status.device_verified);
+            Json(SecretStorageResponse { success: true, status }).into_response()
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<RecoveryError>() {
+                Some(RecoveryError::WrongKey) => StatusCode::UNAUTHORIZED,
+                Some(RecoveryError::NotSetUp) | Some(RecoveryError::Unavailable(_)) => StatusCode::CONFLICT,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("Failed to unlock secret storage: {:#}", e);
+            send_error(status, format!("{:#}", e))
+        }
+    }
+}
+
+async fn backup_status_handler(
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Not connected".to_string());
+    }
+
+    match state.bot.backup_status().await {
+        Ok(status) => Json(BackupResponse { success: true, status, recovery_key: None }).into_response(),
+        Err(e) => {
+            warn!("Failed to get key backup status: {:#}", e);
+            send_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+async fn enable_backup_handler(
+    State(state): State<Arc<AppState>>,
+    payload: Option<Json<EnableBackupRequest>>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Connect before enabling key backup".to_string());
+    }
+
+    let Json(payload) = payload.unwrap_or_default();
+    let recovery_key = payload.recovery_key.as_deref().map(str::trim).filter(|k| !k.is_empty());
+    let passphrase = payload.passphrase.as_deref().filter(|p| !p.is_empty());
+    match state.bot.enable_backup(recovery_key, passphrase).await {
+        Ok((status, recovery_key)) => {
+            info!("Key backup enabled");
+            Json(BackupResponse {
+                success: true,
+                status,
+                recovery_key,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<RecoveryError>() {
+                Some(RecoveryError::WrongKey) => StatusCode::UNAUTHORIZED,
+                Some(RecoveryError::NotSetUp) | Some(RecoveryError::Unavailable(_)) => StatusCode::CONFLICT,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("Failed to enable key backup: {:#}", e);
+            send_error(status, format!("{:#}", e))
+        }
+    }
+}
+
+async fn restore_backup_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<UnlockSecretStorageRequest>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Connect before restoring the key backup".to_string());
+    }
+
+    match state.bot.restore_backup(payload.recovery_key.trim()).await {
+        Ok(status) => {
+            info!("Room keys restored from the key backup");
+            Json(BackupResponse { success: true, status, recovery_key: None }).into_response()
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<RecoveryError>() {
+                Some(RecoveryError::WrongKey) => StatusCode::UNAUTHORIZED,
+                Some(RecoveryError::NotSetUp) | Some(RecoveryError::Unavailable(_)) => StatusCode::CONFLICT,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("Failed to restore the key backup: {:#}", e);
+            send_error(status, format!("{:#}", e))
+        }
+    }
+}
+
+async fn change_passphrase_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ChangePassphraseRequest>,
+) -> impl IntoResponse {
+    let credentials_store = state.credentials_store.clone();
+    let store_path = state.bot.store_path().to_string();
+
+    // Key derivation is deliberately slow, keep it off the async workers
+    let result = tokio::task::spawn_blocking(move || {
+        rotate_passphrase(&credentials_store, &store_path, &payload.current_passphrase, &payload.new_passphrase)
+    })
+    .await
+    .unwrap_or_else(|e| Err(anyhow::anyhow!("Passphrase change task failed: {}", e)));
+
+    match result {
+        Ok(()) => {
+            info!("Database passphrase changed");
+            (
+                StatusCode::OK,
+                Json(LoginResponse {
+                    success: true,
+                    error: None,
+                }),
+            )
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<PassphraseError>() {
+                Some(PassphraseError::Wrong(_)) => StatusCode::UNAUTHORIZED,
+                Some(PassphraseError::Invalid(_)) => StatusCode::BAD_REQUEST,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("Failed to change passphrase: {:#}", e);
+            (
+                status,
+                Json(LoginResponse {
+                    success: false,
+                    error: Some(format!("{:#}", e)),
+                }),
+            )
+        }
+    }
+}
+
+fn room_not_found(room_id: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(SendMessageResponse {
+            success: false,
+            error: Some(format!("Room not configured: {}", room_id)),
+        }),
+    )
+        .into_response()
+}
+
+async fn list_rooms_handler(
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    let messages = state.bot.get_message_history().await;
-    Json(MessageHistoryResponse { messages })
+    let rooms = state.bot.get_rooms().await;
+    Json(RoomsResponse { rooms })
+}
+
+/// Legacy endpoint: history of the first configured room
+async fn get_message_history_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    let room_id = state.bot.default_room_id().to_string();
+    room_history(&state, &room_id, query).await
 }
 
+async fn get_room_history_handler(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    room_history(&state, &room_id, query).await
+}
+
+async fn room_history(state: &AppState, room_id: &str, query: HistoryQuery) -> Response {
+    if !state.bot.has_room(room_id) {
+        return room_not_found(room_id);
+    }
+
+    match state.bot.get_message_history(room_id, query.from.as_deref(), query.limit).await {
+        Ok(page) => {
+            let threads = summarize_threads(&page.messages);
+            Json(MessageHistoryResponse {
+                messages: page.messages,
+                threads,
+                next_token: page.next_token,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            warn!("Failed to load history for {}: {}", room_id, e);
+            send_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
+
+/// Legacy endpoint: send to the first configured room
 async fn send_message_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<SendMessageRequest>,
-) -> impl IntoResponse {
+) -> Response {
+    let room_id = state.bot.default_room_id().to_string();
+    send_room_message(&state, &room_id, payload).await
+}
+
+async fn send_room_message_handler(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Json(payload): Json<SendMessageRequest>,
+) -> Response {
+    send_room_message(&state, &room_id, payload).await
+}
+
+async fn send_room_message(state: &AppState, room_id: &str, payload: SendMessageRequest) -> Response {
+    if !state.bot.has_room(room_id) {
+        return room_not_found(room_id);
+    }
+
     if payload.message.trim().is_empty() {
         return (
             StatusCode::BAD_REQUEST,
@@ -302,40 +1145,396 @@ async fn send_message_handler(
                 success: false,
                 error: Some("Message cannot be empty".to_string()),
             }),
-        );
+        )
+            .into_response();
     }
 
-    match state.bot.send_message(&payload.message).await {
+    let options = MessageOptions {
+        markdown: payload.markdown,
+        reply_to_event_id: payload.reply_to_event_id.as_deref(),
+        thread_root_event_id: payload.thread_root_event_id.as_deref(),
+    };
+
+    match state.bot.send_message(room_id, &payload.message, options).await {
         Ok(_) => (
             StatusCode::OK,
             Json(SendMessageResponse {
                 success: true,
                 error: None,
             }),
-        ),
+        )
+            .into_response(),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(SendMessageResponse {
                 success: false,
                 error: Some(e.to_string()),
             }),
-        ),
+        )
+            .into_response(),
+    }
+}
+
+/// Status code for a failed edit, redaction or reaction
+fn message_error_status(e: &anyhow::Error) -> StatusCode {
+    match e.downcast_ref::<MessageError>() {
+        Some(MessageError::NotFound(_)) | Some(MessageError::ReactionNotFound { .. }) => StatusCode::NOT_FOUND,
+        Some(MessageError::NotOwnMessage(_)) => StatusCode::FORBIDDEN,
+        Some(MessageError::NotRoomMessage(_)) | Some(MessageError::CannotEdit(_)) => StatusCode::BAD_REQUEST,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
+async fn edit_message_handler(
+    State(state): State<Arc<AppState>>,
+    Path(event_id): Path<String>,
+    Json(payload): Json<EditMessageRequest>,
+) -> Response {
+    if payload.message.trim().is_empty() {
+        return send_error(StatusCode::BAD_REQUEST, "Message cannot be empty".to_string());
+    }
+
+    match state.bot.edit_message(&event_id, &payload.message, payload.markdown).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(SendMessageResponse {
+                success: true,
+                error: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Failed to edit message {}: {}", event_id, e);
+            send_error(message_error_status(&e), e.to_string())
+        }
+    }
+}
+
+async fn redact_message_handler(
+    State(state): State<Arc<AppState>>,
+    Path(event_id): Path<String>,
+    Query(query): Query<RedactMessageQuery>,
+) -> Response {
+    match state.bot.redact_message(&event_id, query.reason.as_deref()).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(SendMessageResponse {
+                success: true,
+                error: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Failed to redact message {}: {}", event_id, e);
+            send_error(message_error_status(&e), e.to_string())
+        }
+    }
+}
+
+async fn add_reaction_handler(
+    State(state): State<Arc<AppState>>,
+    Path((event_id, key)): Path<(String, String)>,
+) -> Response {
+    if key.is_empty() {
+        return send_error(StatusCode::BAD_REQUEST, "Reaction key cannot be empty".to_string());
+    }
+
+    match state.bot.add_reaction(&event_id, &key).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(SendMessageResponse {
+                success: true,
+                error: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Failed to react to {}: {}", event_id, e);
+            send_error(message_error_status(&e), e.to_string())
+        }
+    }
+}
+
+async fn remove_reaction_handler(
+    State(state): State<Arc<AppState>>,
+    Path((event_id, key)): Path<(String, String)>,
+) -> Response {
+    match state.bot.remove_reaction(&event_id, &key).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(SendMessageResponse {
+                success: true,
+                error: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Failed to remove reaction from {}: {}", event_id, e);
+            send_error(message_error_status(&e), e.to_string())
+        }
+    }
+}
+
+fn send_error(status: StatusCode, error: String) -> Response {
+    (
+        status,
+        Json(SendMessageResponse {
+            success: false,
+            error: Some(error),
+        }),
+    )
+        .into_response()
+}
+
+async fn upload_attachment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    mut multipart: Multipart,
+) -> Response {
+    if !state.bot.has_room(&room_id) {
+        return room_not_found(&room_id);
+    }
+
+    let mut file = None;
+    let mut dimensions = AttachmentDimensions::default();
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return send_error(e.status(), format!("Invalid upload: {}", e.body_text())),
+        };
+
+        match field.name().unwrap_or_default() {
+            "file" => {
+                let filename = field.file_name().unwrap_or("attachment").to_string();
+                let content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
+                match field.bytes().await {
+                    Ok(data) => file = Some((filename, content_type, data.to_vec())),
+                    Err(e) => return send_error(e.status(), format!("Invalid upload: {}", e.body_text())),
+                }
+            }
+            "width" => dimensions.width = field.text().await.ok().and_then(|v| v.parse().ok()),
+            "height" => dimensions.height = field.text().await.ok().and_then(|v| v.parse().ok()),
+            "duration_ms" => dimensions.duration_ms = field.text().await.ok().and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+    }
+
+    let Some((filename, content_type, data)) = file else {
+        return send_error(StatusCode::BAD_REQUEST, "Missing file field".to_string());
+    };
+
+    if data.is_empty() {
+        return send_error(StatusCode::BAD_REQUEST, "File cannot be empty".to_string());
+    }
+
+    if data.len() as u64 > state.bot.media_max_size() {
+        return send_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("File is larger than the {} byte limit", state.bot.media_max_size()),
+        );
+    }
+
+    let content_type = match content_type.parse::<mime::Mime>() {
+        Ok(mime) => mime,
+        Err(e) => return send_error(StatusCode::BAD_REQUEST, format!("Invalid content type: {}", e)),
+    };
+
+    match state.bot.send_attachment(&room_id, &filename, &content_type, data, dimensions).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(SendMessageResponse {
+                success: true,
+                error: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => send_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Legacy endpoint: stream of the first configured room
 async fn stream_messages_handler(
     State(state): State<Arc<AppState>>,
-) -> Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>> {
+) -> Response {
+    let room_id = state.bot.default_room_id().to_string();
+    room_stream(&state, room_id)
+}
+
+async fn stream_room_messages_handler(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> Response {
+    room_stream(&state, room_id)
+}
+
+fn room_stream(state: &AppState, room_id: String) -> Response {
+    if !state.bot.has_room(&room_id) {
+        return room_not_found(&room_id);
+    }
+
     let rx = state.bot.subscribe();
-    let stream = BroadcastStream::new(rx).filter_map(|msg| match msg {
-        Ok(message) => Some(Ok(Event::default().data(message))),
+    let stream = BroadcastStream::new(rx).filter_map(move |update| match update {
+        Ok(update) if update.room_id() == room_id => match sse_event(&update) {
+            Ok(event) => Some(Ok::<_, Infallible>(event)),
+            Err(e) => {
+                tracing::warn!("Failed to serialize room update: {}", e);
+                None
+            }
+        },
+        Ok(_) => None,
+        Err(e) => {
+            tracing::warn!("Broadcast stream error: {}", e);
+            None
+        }
+    });
+    // Verifications aren't tied to a room, every room stream carries them
+    let verifications = BroadcastStream::new(state.bot.subscribe_verifications()).filter_map(|update| match update {
+        Ok(request) => match Event::default().event("verification").json_data(&request) {
+            Ok(event) => Some(Ok::<_, Infallible>(event)),
+            Err(e) => {
+                tracing::warn!("Failed to serialize verification update: {}", e);
+                None
+            }
+        },
         Err(e) => {
             tracing::warn!("Broadcast stream error: {}", e);
             None
         }
     });
 
-    Sse::new(stream).keep_alive(KeepAlive::default())
+    Sse::new(stream.merge(verifications)).keep_alive(KeepAlive::default()).into_response()
+}
+
+async fn search_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SearchQuery>,
+) -> Response {
+    let Some(archive) = state.archive.clone() else {
+        return send_error(StatusCode::NOT_FOUND, "Message archive is disabled".to_string());
+    };
+
+    if let Some(ref room_id) = query.room_id {
+        if !state.bot.has_room(room_id) {
+            return room_not_found(room_id);
+        }
+    }
+
+    // Only this account's configured rooms, not everything in the shared archive
+    let room_ids = match query.room_id {
+        Some(room_id) => vec![room_id],
+        None => state.bot.room_ids().to_vec(),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    let result = tokio::task::spawn_blocking(move || archive.search(&query.q, &room_ids, limit))
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("Search task failed: {}", e)));
+    match result {
+        Ok(results) => Json(SearchResponse { results }).into_response(),
+        Err(e) => {
+            warn!("Search failed: {}", e);
+            send_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
+
+async fn export_room_handler(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    if !state.bot.has_room(&room_id) {
+        return room_not_found(&room_id);
+    }
+
+    let format = match query.format.as_deref().unwrap_or("jsonl").parse::<ExportFormat>() {
+        Ok(format) => format,
+        Err(e) => return send_error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let range = match DateRange::parse(query.since.as_deref(), query.until.as_deref()) {
+        Ok(range) => range,
+        Err(e) => return send_error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let export = match RoomExport::fetch(&state.bot, &room_id, range).await {
+        Ok(export) => export,
+        Err(e) => {
+            warn!("Failed to export {}: {}", room_id, e);
+            return send_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    };
+    info!("Exporting {} messages from {}", export.messages.len(), room_id);
+
+    // Written on a blocking thread and streamed, so the file is never held in memory
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut out = std::io::BufWriter::new(ChunkWriter(tx.clone()));
+        if let Err(e) = export.write(&mut out, format) {
+            warn!("Export of {} stopped: {}", export.room_id, e);
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    let content_disposition = format!("attachment; filename=\"{}\"", export_file_name(&room_id, format));
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// New messages are sent as unnamed events so plain `onmessage` clients keep
+/// working; edits, redactions and reaction changes are sent as `edit`,
+/// `redaction` and `reactions` events. Verification state changes are sent
+/// as `verification` events by [`room_stream`].
+fn sse_event(update: &RoomUpdate) -> Result<Event, axum::Error> {
+    match update {
+        RoomUpdate::Message(message) => Event::default().json_data(message),
+        RoomUpdate::Edit(message) => Event::default().event("edit").json_data(message),
+        RoomUpdate::Redaction(redaction) => Event::default().event("redaction").json_data(redaction),
+        RoomUpdate::Reactions(update) => Event::default().event("reactions").json_data(update),
+        RoomUpdate::History(reload) => Event::default().event("history").json_data(reload),
+        RoomUpdate::Decrypted(message) => Event::default().event("decrypted").json_data(message),
+    }
+}
+
+async fn get_media_handler(
+    State(state): State<Arc<AppState>>,
+    Path(event_id): Path<String>,
+    Query(query): Query<MediaQuery>,
+) -> Response {
+    match state.bot.download_media(&event_id, query.thumbnail).await {
+        Ok(media) => {
+            let disposition = if media.is_inline_safe() { "inline" } else { "attachment" };
+            let content_disposition = format!("{}; filename=\"{}\"", disposition, media.header_safe_filename());
+            (
+                [
+                    (header::CONTENT_TYPE, media.content_type.clone()),
+                    (header::CONTENT_DISPOSITION, content_disposition),
+                    (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                    (header::CONTENT_SECURITY_POLICY, "sandbox; default-src 'none'".to_string()),
+                    (header::CACHE_CONTROL, "private, max-age=86400".to_string()),
+                ],
+                Body::from_stream(media.body),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<MediaError>() {
+                Some(MediaError::NotFound(_)) | Some(MediaError::NotMedia(_)) => StatusCode::NOT_FOUND,
+                Some(MediaError::TooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("Failed to serve media for {}: {}", event_id, e);
+            (status, e.to_string()).into_response()
+        }
+    }
 }
 
 async fn get_verification_requests_handler(
@@ -353,6 +1552,94 @@ async fn get_sas_status_handler(
     Json(SasStatusResponse { active, sas_info })
 }
 
+async fn start_verification_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<StartVerificationRequest>,
+) -> Response {
+    if !state.bot.is_connected().await {
+        return send_error(StatusCode::CONFLICT, "Connect before requesting a verification".to_string());
+    }
+
+    let device_id = payload.device_id.as_deref().map(str::trim).filter(|d| !d.is_empty());
+    match state.bot.request_verification(payload.user_id.trim(), device_id).await {
+        Ok(request) => Json(StartVerificationResponse { success: true, request }).into_response(),
+        Err(e) => {
+            warn!("Failed to request verification of {}: {:#}", payload.user_id, e);
+            send_error(verification_error_status(&e), format!("{:#}", e))
+        }
+    }
+}
+
+fn verification_error_status(e: &anyhow::Error) -> StatusCode {
+    match e.downcast_ref::<VerificationError>() {
+        Some(VerificationError::NotFound(_)) => StatusCode::NOT_FOUND,
+        Some(VerificationError::Invalid(_)) => StatusCode::BAD_REQUEST,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn verification_qr_code_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<VerificationActionRequest>,
+) -> Response {
+    match state.bot.verification_qr_code(&query.request_id, &query.other_user_id).await {
+        Ok(svg) => (
+            [
+                (header::CONTENT_TYPE, "image/svg+xml"),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            svg,
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Failed to show the QR code of verification {}: {:#}", query.request_id, e);
+            send_error(verification_error_status(&e), format!("{:#}", e))
+        }
+    }
+}
+
+async fn scan_verification_qr_code_handler(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Response {
+    let mut request_id = None;
+    let mut other_user_id = None;
+    let mut image = None;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return send_error(e.status(), format!("Invalid upload: {}", e.body_text())),
+        };
+
+        match field.name().unwrap_or_default() {
+            "request_id" => request_id = field.text().await.ok(),
+            "other_user_id" => other_user_id = field.text().await.ok(),
+            "file" => match field.bytes().await {
+                Ok(data) => image = Some(data.to_vec()),
+                Err(e) => return send_error(e.status(), format!("Invalid upload: {}", e.body_text())),
+            },
+            _ => {}
+        }
+    }
+
+    let (Some(request_id), Some(other_user_id), Some(image)) = (request_id, other_user_id, image) else {
+        return send_error(
+            StatusCode::BAD_REQUEST,
+            "request_id, other_user_id and file fields are required".to_string(),
+        );
+    };
+
+    match state.bot.scan_verification_qr_code(&request_id, &other_user_id, image).await {
+        Ok(()) => Json(VerificationResponse { success: true, error: None }).into_response(),
+        Err(e) => {
+            warn!("Failed to scan the QR code for verification {}: {:#}", request_id, e);
+            send_error(verification_error_status(&e), format!("{:#}", e))
+        }
+    }
+}
+
 async fn accept_verification_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<VerificationActionRequest>,
@@ -419,8 +1706,8 @@ async fn cancel_verification_handler(
     }
 }
 
-pub async fn start_server(host: &str, port: u16, state: AppState) -> anyhow::Result<()> {
-    let app = create_router(state);
+pub async fn start_server(host: &str, port: u16, accounts: Vec<AppState>, auth: Option<AuthConfig>) -> anyhow::Result<()> {
+    let app = create_router(accounts, auth);
     let addr = format!("{}:{}", host, port);
     info!("Starting web server on {}", addr);
 